@@ -1,17 +1,19 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     convert::TryInto,
     io::{self, Read, Write},
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
     option, vec,
 };
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
 
-#[cfg(feature = "i2p")]
-use i2p::net::{I2pSocketAddr, ToI2pSocketAddrs};
 #[cfg(any(feature = "tor", feature = "i2p"))]
 use koibumi_base32 as base32;
-#[cfg(any(feature = "tor"))]
-use tor_stream::TorStream;
+#[cfg(feature = "tor")]
+use sha3::{Digest, Sha3_256};
+
+use crate::transport::Connectors;
 
 pub trait ToPeerAddrs {
     /// Returned iterator over peer addresses which this type may correspond
@@ -81,31 +83,6 @@ impl ToPeerAddrs for SocketAddr {
     }
 }
 
-#[cfg(feature = "i2p")]
-///
-/// ```
-/// use i2p::net::{I2pSocketAddr, ToI2pSocketAddrs};
-/// use zeronet_protocol::ToPeerAddrs;
-///
-/// let socket_addr = "udhdrtrcetjm5sxzskjyr5ztpeszydbh4dpl3pl4utgqqw2v4jna.b32.i2p:4321".to_socket_addrs().unwrap().next().unwrap();
-/// let peer_addrs = ToPeerAddrs::to_peer_addrs(
-///   &socket_addr as &dyn ToI2pSocketAddrs<Iter = std::option::IntoIter<I2pSocketAddr>>
-/// );
-/// assert!(peer_addrs.is_ok());
-/// assert!(peer_addrs.unwrap().len() == 1);
-/// ```
-impl<I: Iterator<Item = I2pSocketAddr>> ToPeerAddrs for dyn ToI2pSocketAddrs<Iter = I> {
-    type Iter = vec::IntoIter<PeerAddr>;
-    fn to_peer_addrs(&self) -> std::io::Result<Self::Iter> {
-        let addrs: Vec<_> = self
-            .to_socket_addrs()?
-            .map(|addr| PeerAddr::I2PB32(addr.dest().string(), addr.port()))
-            .collect();
-
-        Ok(addrs.into_iter())
-    }
-}
-
 #[derive(Debug, Error)]
 pub enum ParseError {
     #[error("Error parsing int: `{0}`")]
@@ -120,12 +97,20 @@ pub enum ParseError {
     UnrecognizedAddressFormat,
     #[error("Address is missing port")]
     MissingPort,
+    #[error("Address `{address}` is not valid {encoding}")]
+    InvalidEncoding { address: String, encoding: String },
+    #[error("Onion address `{address}` has unsupported version {version}")]
+    InvalidOnionVersion { address: String, version: u8 },
+    #[error("Onion address `{0}` has a wrong checksum")]
+    InvalidChecksum(String),
 }
 
 #[derive(Debug, Error)]
 pub enum AddressError {
     #[error("Error unpacking address")]
     UnpackError,
+    #[error("Address `{0}` cannot be packed")]
+    PackError(String),
     #[error("Unexpected number of bytes {0}")]
     InvalidBytearray(usize),
     #[error("Error creating tcp stream read-write pair")]
@@ -134,8 +119,39 @@ pub enum AddressError {
     IoError(#[from] std::io::Error),
     #[error("Address is of an invalid type")]
     InvalidAddressType,
+    #[error("SOCKS proxy refused the connection with code {0}")]
+    ProxyRefused(u8),
+    #[error("Unexpected reply from SOCKS proxy")]
+    ProxyProtocol,
+    #[error("SOCKS proxy rejected the credentials")]
+    ProxyAuth,
+    #[error("Host name `{0}` is too long for SOCKS")]
+    ProxyHostTooLong(String),
+    #[error("SAM bridge replied `{result}`: {message}")]
+    SamError { result: String, message: String },
+    #[error("Unexpected reply from SAM bridge")]
+    SamProtocol,
+    #[error("`{0}` cannot be sent to a SAM bridge")]
+    SamArgument(String),
+    #[error("Hash id {0} does not fit in two bytes")]
+    HashIdOutOfRange(usize),
+    #[error("{0} bytes may be an I2P or a Lokinet address, use `unpack_as`")]
+    AmbiguousBytearray(usize),
+    #[error("No connector registered for {0:?} addresses")]
+    NoConnector(AddrKind),
+    #[error("Connector for {0:?} addresses cannot connect without a runtime")]
+    NoBlockingConnector(AddrKind),
 }
 
+/// Read half of a connection opened by `PeerAddr::get_pair_async`.
+pub type AsyncReader = Box<dyn AsyncRead + Send + Unpin>;
+/// Write half of a connection opened by `PeerAddr::get_pair_async`.
+pub type AsyncWriter = Box<dyn AsyncWrite + Send + Unpin>;
+/// Read half of a connection opened by `PeerAddr::get_pair`.
+pub type BlockingReader = Box<dyn Read + Send>;
+/// Write half of a connection opened by `PeerAddr::get_pair`.
+pub type BlockingWriter = Box<dyn Write + Send>;
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum PeerAddr {
     IPV4([u8; 4], u16),
@@ -150,6 +166,21 @@ pub enum PeerAddr {
     Loki(String, u16),
 }
 
+/// The family of a `PeerAddr`, without the address itself.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum AddrKind {
+    IPV4,
+    IPV6,
+    #[cfg(feature = "tor")]
+    OnionV2,
+    #[cfg(feature = "tor")]
+    OnionV3,
+    #[cfg(feature = "i2p")]
+    I2PB32,
+    #[cfg(feature = "loki")]
+    Loki,
+}
+
 impl From<SocketAddr> for PeerAddr {
     fn from(address: SocketAddr) -> PeerAddr {
         match address {
@@ -169,7 +200,7 @@ impl TryInto<SocketAddr> for PeerAddr {
                 port,
             )),
             PeerAddr::IPV6(ip, port) => Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)),
-            #[cfg(any(feature = "i2p", feature = "tor"))]
+            #[cfg(any(feature = "i2p", feature = "tor", feature = "loki"))]
             _ => Err(AddressError::InvalidAddressType),
         }
     }
@@ -188,7 +219,7 @@ impl TryInto<SocketAddr> for &PeerAddr {
                 IpAddr::V6(Ipv6Addr::from(ip.clone())),
                 *port,
             )),
-            #[cfg(any(feature = "i2p", feature = "tor"))]
+            #[cfg(any(feature = "i2p", feature = "tor", feature = "loki"))]
             _ => Err(AddressError::InvalidAddressType),
         }
     }
@@ -220,8 +251,25 @@ impl PeerAddr {
             #[cfg(feature = "tor")]
             if let Some(address) = parts[0].strip_suffix(".onion") {
                 return match address.len() {
-                    16 => Ok(PeerAddr::OnionV2(address.to_string(), port)),
-                    56 => Ok(PeerAddr::OnionV3(address.to_string(), port)),
+                    16 => {
+                        if base32::decode(address.to_lowercase()).is_err() {
+                            return Err(ParseError::InvalidEncoding {
+                                address: address.to_string(),
+                                encoding: "base32".to_string(),
+                            });
+                        }
+                        Ok(PeerAddr::OnionV2(address.to_string(), port))
+                    }
+                    56 => {
+                        let bytes = base32::decode(address.to_lowercase()).map_err(|_| {
+                            ParseError::InvalidEncoding {
+                                address: address.to_string(),
+                                encoding: "base32".to_string(),
+                            }
+                        })?;
+                        verify_onion_v3(address, &bytes)?;
+                        Ok(PeerAddr::OnionV3(address.to_string(), port))
+                    }
                     l => Err(ParseError::WrongLength {
                         address: address.to_string(),
                         length: l,
@@ -231,10 +279,30 @@ impl PeerAddr {
             }
             #[cfg(feature = "i2p")]
             if let Some(address) = parts[0].strip_suffix(".b32.i2p") {
-                return Ok(PeerAddr::I2PB32(address.to_string(), port));
+                return match address.len() {
+                    52 => Ok(PeerAddr::I2PB32(address.to_string(), port)),
+                    l => Err(ParseError::WrongLength {
+                        address: address.to_string(),
+                        length: l,
+                        expected: "52".to_string(),
+                    }),
+                };
             }
             #[cfg(feature = "loki")]
             if let Some(address) = parts[0].strip_suffix(".loki") {
+                if address.len() != 52 {
+                    return Err(ParseError::WrongLength {
+                        address: address.to_string(),
+                        length: address.len(),
+                        expected: "52".to_string(),
+                    });
+                }
+                if zbase32::decode(&address.to_lowercase()).is_none() {
+                    return Err(ParseError::InvalidEncoding {
+                        address: address.to_string(),
+                        encoding: "z-base32".to_string(),
+                    });
+                }
                 return Ok(PeerAddr::Loki(address.to_string(), port));
             }
         }
@@ -243,6 +311,9 @@ impl PeerAddr {
     }
 
     /// Unpack the address from bytes
+    ///
+    /// With both the `i2p` and `loki` features, 34 bytes may be either and
+    /// have to be unpacked with `unpack_as`.
     /// ```
     /// use zeronet_protocol::PeerAddr;
     ///
@@ -276,77 +347,97 @@ impl PeerAddr {
                 let address = base32::encode(&array);
                 Ok(PeerAddr::OnionV2(address, port))
             }
-            #[cfg(feature = "i2p")]
-            34 => {
-                let port = u16::from_le_bytes(bytes[32..34].try_into().unwrap());
-                let mut array = [0u8; 32];
-                array.copy_from_slice(&bytes[..32]);
-                let address = base32::encode(&array);
-                Ok(PeerAddr::I2PB32(address, port))
-            }
+            #[cfg(all(feature = "i2p", not(feature = "loki")))]
+            34 => PeerAddr::unpack_i2p(bytes),
+            #[cfg(all(feature = "loki", not(feature = "i2p")))]
+            34 => PeerAddr::unpack_loki(bytes),
+            // Lokinet and I2P addresses are both 32 bytes, only `unpack_as`
+            // can tell them apart.
+            #[cfg(all(feature = "i2p", feature = "loki"))]
+            34 => Err(AddressError::AmbiguousBytearray(34)),
             #[cfg(feature = "tor")]
             37 => {
                 let port = u16::from_le_bytes(bytes[35..37].try_into().unwrap());
                 let mut array = [0u8; 35];
                 array.copy_from_slice(&bytes[..35]);
                 let address = base32::encode(&array);
+                verify_onion_v3(&address, &array).map_err(|_| AddressError::UnpackError)?;
                 Ok(PeerAddr::OnionV3(address, port))
             }
             l => Err(AddressError::InvalidBytearray(l)),
         }
     }
 
+    /// Unpack bytes that are known to hold an address of `kind`, as in
+    /// the per-network peer lists of pex and announce.
+    pub fn unpack_as(bytes: &[u8], kind: AddrKind) -> Result<PeerAddr, AddressError> {
+        #[cfg(feature = "i2p")]
+        if kind == AddrKind::I2PB32 {
+            return PeerAddr::unpack_i2p(bytes);
+        }
+        #[cfg(feature = "loki")]
+        if kind == AddrKind::Loki {
+            return PeerAddr::unpack_loki(bytes);
+        }
+        let address = PeerAddr::unpack(bytes)?;
+        if address.kind() != kind {
+            return Err(AddressError::InvalidBytearray(bytes.len()));
+        }
+        Ok(address)
+    }
+
+    #[cfg(feature = "i2p")]
+    fn unpack_i2p(bytes: &[u8]) -> Result<PeerAddr, AddressError> {
+        if bytes.len() != 34 {
+            return Err(AddressError::InvalidBytearray(bytes.len()));
+        }
+        let port = u16::from_le_bytes(bytes[32..34].try_into().unwrap());
+        Ok(PeerAddr::I2PB32(base32::encode(&bytes[..32]), port))
+    }
+
+    #[cfg(feature = "loki")]
+    fn unpack_loki(bytes: &[u8]) -> Result<PeerAddr, AddressError> {
+        if bytes.len() != 34 {
+            return Err(AddressError::InvalidBytearray(bytes.len()));
+        }
+        let port = u16::from_le_bytes(bytes[32..34].try_into().unwrap());
+        Ok(PeerAddr::Loki(zbase32::encode(&bytes[..32]), port))
+    }
+
     /// Pack the address into bytes
     /// ```
     /// use zeronet_protocol::PeerAddr;
     ///
     /// let address = PeerAddr::parse("127.0.0.1:4321").expect("could not parse address");
-    /// let packed = address.pack();
+    /// let packed = address.pack().unwrap();
     ///
     /// assert_eq!(packed, [127, 0, 0, 1, 225, 16]);
     /// ```
-    pub fn pack(&self) -> Vec<u8> {
-        match self {
-            PeerAddr::IPV4(address, port) => {
-                let mut bytes = address.to_vec();
-                bytes.append(&mut port.to_le_bytes().to_vec());
-                bytes
-            }
-            PeerAddr::IPV6(address, port) => {
-                let mut bytes = address.to_vec();
-                bytes.append(&mut port.to_le_bytes().to_vec());
-                bytes
-            }
+    pub fn pack(&self) -> Result<Vec<u8>, AddressError> {
+        let (mut bytes, port) = match self {
+            PeerAddr::IPV4(address, port) => (address.to_vec(), port),
+            PeerAddr::IPV6(address, port) => (address.to_vec(), port),
             #[cfg(feature = "tor")]
-            PeerAddr::OnionV2(address, port) => {
-                let address = address.to_lowercase();
-                let mut bytes = base32::decode(address).unwrap();
-                bytes.append(&mut port.to_le_bytes().to_vec());
-                bytes
-            }
+            PeerAddr::OnionV2(address, port) => (decode_base32(address, 10)?, port),
             #[cfg(feature = "tor")]
             PeerAddr::OnionV3(address, port) => {
-                let address = address.to_lowercase();
-                let mut bytes = base32::decode(address).unwrap();
-                bytes.append(&mut port.to_le_bytes().to_vec());
-                bytes
+                let bytes = decode_base32(address, 35)?;
+                verify_onion_v3(address, &bytes)
+                    .map_err(|_| AddressError::PackError(address.clone()))?;
+                (bytes, port)
             }
             #[cfg(feature = "i2p")]
-            PeerAddr::I2PB32(address, port) => {
-                let address = address.to_lowercase();
-                let mut bytes = base32::decode(address).unwrap();
-                bytes.append(&mut port.to_le_bytes().to_vec());
-                bytes
-            }
+            PeerAddr::I2PB32(address, port) => (decode_base32(address, 32)?, port),
             #[cfg(feature = "loki")]
-            PeerAddr::Loki(_address, _port) => {
-                unimplemented!()
-                //   let address = address.to_lowercase();
-                //   let mut bytes = base32::decode(address).unwrap();
-                //   bytes.append(&mut port.to_le_bytes().to_vec());
-                //   bytes
+            PeerAddr::Loki(address, port) => {
+                let bytes = zbase32::decode(&address.to_lowercase())
+                    .filter(|bytes| bytes.len() == 32)
+                    .ok_or_else(|| AddressError::PackError(address.clone()))?;
+                (bytes, port)
             }
-        }
+        };
+        bytes.extend_from_slice(&port.to_le_bytes());
+        Ok(bytes)
     }
 
     /// To string
@@ -377,41 +468,53 @@ impl PeerAddr {
         }
     }
 
-    pub fn get_pair(&self) -> Result<(Box<dyn Read + Send>, Box<dyn Write + Send>), AddressError> {
+    /// Connect to the address with the connector `connectors` has for its
+    /// kind, blocking the current thread.
+    pub fn get_pair(
+        &self,
+        connectors: &Connectors,
+    ) -> Result<(BlockingReader, BlockingWriter), AddressError> {
+        connectors.connect_blocking(self)
+    }
+
+    /// Connect to the address without blocking the runtime, with the
+    /// connector `connectors` has for its kind.
+    pub async fn get_pair_async(
+        &self,
+        connectors: &Connectors,
+    ) -> Result<(AsyncReader, AsyncWriter), AddressError> {
+        connectors.connect(self).await
+    }
+
+    pub fn kind(&self) -> AddrKind {
         match self {
-            PeerAddr::IPV4(_, _) | PeerAddr::IPV6(_, _) => {
-                let socket = TcpStream::connect(self.to_string())?;
-                return Ok((Box::new(socket.try_clone()?), Box::new(socket)));
-            }
+            PeerAddr::IPV4(_, _) => AddrKind::IPV4,
+            PeerAddr::IPV6(_, _) => AddrKind::IPV6,
             #[cfg(feature = "tor")]
-            PeerAddr::OnionV2(_, _) | PeerAddr::OnionV3(_, _) => {
-                let (reader, writer) =
-                    TorStream::connect(self.to_string().as_str())?.split_boxed()?;
-                return Ok((reader, writer));
-            }
-            #[cfg(any(feature = "i2p"))]
-            _ => Err(AddressError::TcpStreamError),
+            PeerAddr::OnionV2(_, _) => AddrKind::OnionV2,
+            #[cfg(feature = "tor")]
+            PeerAddr::OnionV3(_, _) => AddrKind::OnionV3,
+            #[cfg(feature = "i2p")]
+            PeerAddr::I2PB32(_, _) => AddrKind::I2PB32,
+            #[cfg(feature = "loki")]
+            PeerAddr::Loki(_, _) => AddrKind::Loki,
         }
     }
 
-    pub async fn get_pair_async(
-        &self,
-    ) -> Result<(Box<dyn Read + Send>, Box<dyn Write + Send>), AddressError> {
+    /// The address without its port, as ZeroNet writes it in `target_ip`:
+    /// IPv6 addresses are not bracketed.
+    pub fn host(&self) -> String {
         match self {
-            PeerAddr::IPV4(_, _) | PeerAddr::IPV6(_, _) => {
-                use tokio::net::TcpStream as AsyncTcpStream;
-                let socket = AsyncTcpStream::connect(self.to_string()).await?;
-                let socket = socket.into_std()?;
-                return Ok((Box::new(socket.try_clone()?), Box::new(socket)));
-            }
+            PeerAddr::IPV4(ip, _) => Ipv4Addr::from(*ip).to_string(),
+            PeerAddr::IPV6(ip, _) => Ipv6Addr::from(*ip).to_string(),
             #[cfg(feature = "tor")]
-            PeerAddr::OnionV2(_, _) | PeerAddr::OnionV3(_, _) => {
-                let (reader, writer) =
-                    TorStream::connect(self.to_string().as_str())?.split_boxed()?;
-                return Ok((reader, writer));
+            PeerAddr::OnionV2(address, _) | PeerAddr::OnionV3(address, _) => {
+                format!("{}.onion", address)
             }
-            #[cfg(any(feature = "i2p"))]
-            _ => Err(AddressError::TcpStreamError),
+            #[cfg(feature = "i2p")]
+            PeerAddr::I2PB32(address, _) => format!("{}.b32.i2p", address),
+            #[cfg(feature = "loki")]
+            PeerAddr::Loki(address, _) => format!("{}.loki", address),
         }
     }
 
@@ -454,7 +557,7 @@ impl PeerAddr {
     pub fn is_clearnet(&self) -> bool {
         match self {
             PeerAddr::IPV4(_, _) | PeerAddr::IPV6(_, _) => true,
-            #[cfg(any(feature = "tor", feature = "i2p"))]
+            #[cfg(any(feature = "tor", feature = "i2p", feature = "loki"))]
             _ => false,
         }
     }
@@ -493,7 +596,7 @@ mod tests {
         // won't go unnoticed, particularly as that could mean they can be
         // simplified.
         let address = PeerAddr::parse("127.0.0.1:8001").unwrap();
-        let bytes = address.pack();
+        let bytes = address.pack().unwrap();
         let serialized_bytes = rmp_serde::to_vec(&bytes).unwrap();
 
         let byte_buf = ByteBuf::from(bytes.clone());
@@ -519,7 +622,7 @@ mod tests {
     #[test]
     fn test_pack_ipv4() {
         let address = PeerAddr::parse("127.0.0.1:4321").expect("could not parse address");
-        let packed = address.pack();
+        let packed = address.pack().unwrap();
 
         assert_eq!(packed, [127, 0, 0, 1, 225, 16]);
     }
@@ -528,7 +631,7 @@ mod tests {
     fn test_pack_ipv6() {
         let address_string = "[1001:2002:3003:4004:5005:6006:7007:8008]:4321".to_string();
         let address = PeerAddr::parse(&address_string).expect("could not parse address");
-        let packed = address.pack();
+        let packed = address.pack().unwrap();
         let unpacked = PeerAddr::unpack(&packed).expect("could not unpack address");
 
         assert_eq!(
@@ -542,7 +645,7 @@ mod tests {
     fn test_pack_ipv6_shorthand() {
         let address_string = "[2001:db8::ff00:42:8329]:4321".to_string();
         let address = PeerAddr::parse(&address_string).expect("could not parse address");
-        let packed = address.pack();
+        let packed = address.pack().unwrap();
         let unpacked = PeerAddr::unpack(&packed).expect("could not unpack address");
 
         assert_eq!(
@@ -552,12 +655,138 @@ mod tests {
         assert_eq!(unpacked.to_string(), address_string);
     }
 
+    #[test]
+    fn test_pack_invalid() {
+        #[cfg(feature = "tor")]
+        for address in [
+            PeerAddr::OnionV2("not base32!".to_string(), 1),
+            PeerAddr::OnionV3("ytcnzluhaxidtbf4".to_string(), 1),
+            PeerAddr::OnionV3("a".repeat(56), 1),
+        ] {
+            assert!(matches!(address.pack(), Err(AddressError::PackError(_))));
+        }
+        #[cfg(feature = "i2p")]
+        assert!(matches!(
+            PeerAddr::I2PB32("ytcnzluhaxidtbf4".to_string(), 1).pack(),
+            Err(AddressError::PackError(_))
+        ));
+        #[cfg(feature = "loki")]
+        assert!(matches!(
+            PeerAddr::Loki("l".repeat(52), 1).pack(),
+            Err(AddressError::PackError(_))
+        ));
+        let address = PeerAddr::IPV4([127, 0, 0, 1], 1);
+        assert_eq!(address.pack().unwrap(), [127, 0, 0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn test_serde_forms() {
+        let address = PeerAddr::parse("127.0.0.1:4321").unwrap();
+
+        let json = serde_json::to_string(&address).unwrap();
+        assert_eq!(json, "\"127.0.0.1:4321\"");
+        assert_eq!(serde_json::from_str::<PeerAddr>(&json).unwrap(), address);
+
+        let packed = rmp_serde::to_vec(&address).unwrap();
+        let bytes = ByteBuf::from(address.pack().unwrap());
+        assert_eq!(packed, rmp_serde::to_vec(&bytes).unwrap());
+        assert_eq!(rmp_serde::from_slice::<PeerAddr>(&packed).unwrap(), address);
+    }
+
+    #[test]
+    fn test_serde_peer_list() {
+        #[derive(Serialize)]
+        struct Packed {
+            peers: Vec<ByteBuf>,
+        }
+        #[derive(Deserialize)]
+        struct Typed {
+            peers: Vec<PeerAddr>,
+        }
+        let peers = vec![
+            PeerAddr::parse("127.0.0.1:4321").unwrap(),
+            PeerAddr::parse("[2001:db8::ff00:42:8329]:4321").unwrap(),
+        ];
+        let packed = Packed {
+            peers: peers
+                .iter()
+                .map(|peer| ByteBuf::from(peer.pack().unwrap()))
+                .collect(),
+        };
+        let bytes = rmp_serde::to_vec_named(&packed).unwrap();
+        let typed: Typed = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(typed.peers, peers);
+
+        let result = rmp_serde::from_slice::<PeerAddr>(
+            &rmp_serde::to_vec(&ByteBuf::from(vec![1, 2])).unwrap(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_packed_str() {
+        let peer = PeerAddr::parse("10.0.0.1:15441").unwrap();
+        let packed = String::from_utf8(peer.pack().unwrap()).unwrap();
+        let bytes = rmp_serde::to_vec(&packed).unwrap();
+        let result: PeerAddr = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(result, peer);
+    }
+
+    #[tokio::test]
+    async fn test_get_pair_async() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = PeerAddr::from(listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+
+        let (mut reader, mut writer) = address
+            .get_pair_async(&Connectors::default())
+            .await
+            .unwrap();
+        writer.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_get_pair() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = PeerAddr::from(listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).unwrap();
+            socket.write_all(&buf).unwrap();
+        });
+
+        let (mut reader, mut writer) = address.get_pair(&Connectors::default()).unwrap();
+        writer.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        server.join().unwrap();
+
+        let result = address.get_pair(&Connectors::empty());
+        assert!(matches!(
+            result,
+            Err(AddressError::NoConnector(AddrKind::IPV4))
+        ));
+    }
+
     #[cfg(feature = "tor")]
     #[test]
     fn test_pack_onionv2() {
         let address_string = "ytcnzluhaxidtbf4.onion:4321".to_string();
         let address = PeerAddr::parse(&address_string).expect("could not parse address");
-        let packed = address.pack();
+        let packed = address.pack().unwrap();
         let unpacked = PeerAddr::unpack(&packed).expect("could not unpack address");
 
         assert_eq!(
@@ -573,7 +802,7 @@ mod tests {
         let address_string =
             "trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion:4321".to_string();
         let address = PeerAddr::parse(&address_string).expect("could not parse address");
-        let packed = address.pack();
+        let packed = address.pack().unwrap();
         let unpacked = PeerAddr::unpack(&packed).expect("could not unpack address");
 
         assert_eq!(
@@ -586,14 +815,51 @@ mod tests {
         assert_eq!(unpacked.to_string(), address_string);
     }
 
+    #[cfg(feature = "tor")]
+    #[test]
+    fn test_parse_onionv3_invalid() {
+        // Last character changed, which breaks the version byte.
+        let result =
+            PeerAddr::parse("trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgia.onion:1");
+        assert!(matches!(
+            result,
+            Err(ParseError::InvalidOnionVersion { version: 0, .. })
+        ));
+        // First character changed, which breaks the checksum.
+        let result =
+            PeerAddr::parse("arackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion:1");
+        assert!(matches!(result, Err(ParseError::InvalidChecksum(_))));
+        let result =
+            PeerAddr::parse("trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrg1d.onion:1");
+        assert!(matches!(result, Err(ParseError::InvalidEncoding { .. })));
+        let result = PeerAddr::parse("ytcnzluhaxidtbf!.onion:1");
+        assert!(matches!(result, Err(ParseError::InvalidEncoding { .. })));
+    }
+
+    #[cfg(feature = "tor")]
+    #[test]
+    fn test_unpack_onionv3_invalid() {
+        let mut bytes =
+            PeerAddr::parse("trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion:4321")
+                .unwrap()
+                .pack()
+                .unwrap();
+        bytes[0] ^= 1;
+        assert!(matches!(
+            PeerAddr::unpack(&bytes),
+            Err(AddressError::UnpackError)
+        ));
+    }
+
     #[cfg(feature = "i2p")]
     #[test]
     fn test_pack_i2pb32() {
         let address_string =
             "udhdrtrcetjm5sxzskjyr5ztpeszydbh4dpl3pl4utgqqw2v4jna.b32.i2p:4321".to_string();
         let address = PeerAddr::parse(&address_string).expect("could not parse address");
-        let packed = address.pack();
-        let unpacked = PeerAddr::unpack(&packed).expect("could not unpack address");
+        let packed = address.pack().unwrap();
+        let unpacked =
+            PeerAddr::unpack_as(&packed, AddrKind::I2PB32).expect("could not unpack address");
 
         assert_eq!(
             packed,
@@ -605,24 +871,154 @@ mod tests {
         assert_eq!(unpacked.to_string(), address_string);
     }
 
+    #[cfg(all(feature = "i2p", feature = "loki"))]
+    #[test]
+    fn test_unpack_ambiguous() {
+        let packed =
+            PeerAddr::parse("udhdrtrcetjm5sxzskjyr5ztpeszydbh4dpl3pl4utgqqw2v4jna.b32.i2p:4321")
+                .unwrap()
+                .pack()
+                .unwrap();
+        assert!(matches!(
+            PeerAddr::unpack(&packed),
+            Err(AddressError::AmbiguousBytearray(34))
+        ));
+        assert!(PeerAddr::unpack_as(&packed, AddrKind::I2PB32)
+            .unwrap()
+            .is_i2p());
+        assert!(PeerAddr::unpack_as(&packed, AddrKind::Loki)
+            .unwrap()
+            .is_loki());
+    }
+
+    #[cfg(feature = "i2p")]
+    #[test]
+    fn test_parse_i2pb32_wrong_length() {
+        let result = PeerAddr::parse("udhdrtrcetjm5sxzskjyr5ztpeszydbh4dpl3.b32.i2p:4321");
+        assert!(matches!(
+            result,
+            Err(ParseError::WrongLength { length: 37, .. })
+        ));
+    }
+
     #[cfg(feature = "loki")]
     #[test]
     fn test_pack_loki() {
         let address_string =
             "dw68y1xhptqbhcm5s8aaaip6dbopykagig5q5u1za4c7pzxto77y.loki:4321".to_string();
         let address = PeerAddr::parse(&address_string).expect("could not parse address");
-        let packed = address.pack();
-        let unpacked = PeerAddr::unpack(&packed).expect("could not unpack address");
+        let packed = address.pack().unwrap();
+        let unpacked =
+            PeerAddr::unpack_as(&packed, AddrKind::Loki).expect("could not unpack address");
 
         assert_eq!(
             packed,
             [
-                160, 206, 56, 206, 34, 36, 210, 206, 202, 249, 146, 147, 136, 247, 51, 121, 37,
-                156, 12, 39, 224, 222, 189, 189, 124, 164, 205, 8, 91, 85, 226, 90, 225, 16
+                29, 60, 112, 73, 252, 108, 92, 30, 49, 123, 177, 241, 140, 85, 190, 24, 96, 208,
+                43, 6, 169, 182, 237, 206, 87, 198, 153, 214, 221, 241, 135, 122, 225, 16
             ]
         );
         assert_eq!(unpacked.to_string(), address_string);
     }
+
+    #[cfg(feature = "loki")]
+    #[test]
+    fn test_parse_loki_mixed_case() {
+        let lower = PeerAddr::parse("dw68y1xhptqbhcm5s8aaaip6dbopykagig5q5u1za4c7pzxto77y.loki:1")
+            .expect("could not parse address");
+        let mixed = PeerAddr::parse("DW68Y1XHPTQBHCM5s8aaaip6dbopykagig5q5u1za4c7pzxto77y.loki:1")
+            .expect("could not parse address");
+        assert_eq!(mixed.pack().unwrap(), lower.pack().unwrap());
+    }
+
+    #[cfg(feature = "loki")]
+    #[test]
+    fn test_parse_loki_invalid() {
+        let result = PeerAddr::parse("lw68y1xhptqbhcm5s8aaaip6dbopykagig5q5u1za4c7pzxto77y.loki:1");
+        assert!(matches!(result, Err(ParseError::InvalidEncoding { .. })));
+        let result = PeerAddr::parse("dw68y1xhptqbhcm5.loki:1");
+        assert!(matches!(result, Err(ParseError::WrongLength { .. })));
+    }
+}
+
+/// Decode the base32 part of an onion or I2P address, which must hold
+/// exactly `len` bytes.
+#[cfg(any(feature = "tor", feature = "i2p"))]
+fn decode_base32(address: &str, len: usize) -> Result<Vec<u8>, AddressError> {
+    match base32::decode(address.to_lowercase()) {
+        Ok(bytes) if bytes.len() == len => Ok(bytes),
+        _ => Err(AddressError::PackError(address.to_string())),
+    }
+}
+
+/// Check the decoded bytes of a v3 onion address: a 32 byte ed25519 public
+/// key, two bytes of checksum and the version, which must be 3.
+#[cfg(feature = "tor")]
+fn verify_onion_v3(address: &str, bytes: &[u8]) -> Result<(), ParseError> {
+    if bytes.len() != 35 {
+        return Err(ParseError::InvalidEncoding {
+            address: address.to_string(),
+            encoding: "onion v3".to_string(),
+        });
+    }
+    let (pubkey, checksum, version) = (&bytes[..32], &bytes[32..34], bytes[34]);
+    if version != 3 {
+        return Err(ParseError::InvalidOnionVersion {
+            address: address.to_string(),
+            version,
+        });
+    }
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update([version]);
+    if hasher.finalize()[..2] != *checksum {
+        return Err(ParseError::InvalidChecksum(address.to_string()));
+    }
+    Ok(())
+}
+
+/// The z-base32 encoding Lokinet uses for its addresses.
+#[cfg(feature = "loki")]
+mod zbase32 {
+    const ALPHABET: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+
+    pub fn encode(bytes: &[u8]) -> String {
+        let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+        let (mut buffer, mut bits) = (0u16, 0);
+        for &byte in bytes {
+            buffer = (buffer << 8) | byte as u16;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+            }
+        }
+        if bits > 0 {
+            encoded.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+        }
+        encoded
+    }
+
+    /// `None` if `encoded` has characters outside the alphabet or leaves
+    /// non-zero bits over.
+    pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+        let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+        let (mut buffer, mut bits) = (0u16, 0);
+        for c in encoded.bytes() {
+            let value = ALPHABET.iter().position(|&a| a == c)? as u16;
+            buffer = (buffer << 5) | value;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                decoded.push((buffer >> bits) as u8);
+            }
+        }
+        if buffer & ((1 << bits) - 1) != 0 {
+            return None;
+        }
+        Some(decoded)
+    }
 }
 
 impl std::fmt::Display for PeerAddr {
@@ -642,3 +1038,52 @@ impl std::fmt::Display for PeerAddr {
         write!(f, "{} [{}]", address_type, self.to_string())
     }
 }
+
+/// Packed bytes for binary formats such as msgpack, `host:port` for
+/// human-readable ones such as JSON.
+impl Serialize for PeerAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            let bytes = self.pack().map_err(serde::ser::Error::custom)?;
+            serializer.serialize_bytes(&bytes)
+        }
+    }
+}
+
+/// Accepts either form regardless of the format.
+impl<'de> Deserialize<'de> for PeerAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PeerAddr, D::Error> {
+        deserializer.deserialize_any(PeerAddrVisitor)
+    }
+}
+
+struct PeerAddrVisitor;
+
+impl<'de> de::Visitor<'de> for PeerAddrVisitor {
+    type Value = PeerAddr;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a packed address or a `host:port` string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<PeerAddr, E> {
+        // Packed addresses that happen to be valid UTF-8 may arrive as str.
+        PeerAddr::parse(v)
+            .or_else(|err| PeerAddr::unpack(v.as_bytes()).map_err(|_| err))
+            .map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<PeerAddr, E> {
+        PeerAddr::unpack(v).map_err(E::custom)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<PeerAddr, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(64));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        self.visit_bytes(&bytes)
+    }
+}
@@ -1,10 +1,76 @@
+/// Protocol revision advertised in handshakes.
+pub const PROTOCOL: &str = "v2";
+/// ZeroNet release whose behaviour we implement, advertised in handshakes
+/// so that peers enable the same features for us.
+pub const VERSION: &str = "0.7.2";
+pub const REV: usize = 4555;
+/// Client code and version at the start of our peer ids.
+pub const PEER_ID_PREFIX: &str = "-DN0100-";
+
+/// A new ZeroNet-style peer id: `PEER_ID_PREFIX` followed by 12 random
+/// alphanumeric characters. Generate one at startup and reuse it for every
+/// connection.
+pub fn peer_id() -> String {
+    use std::{collections::hash_map::RandomState, hash::BuildHasher};
+
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    // The hasher keys are seeded from the system's random source.
+    let state = RandomState::new();
+    let random: String = (0..12u8)
+        .map(|i| ALPHABET[(state.hash_one(i) % ALPHABET.len() as u64) as usize] as char)
+        .collect();
+    format!("{}{}", PEER_ID_PREFIX, random)
+}
+
 pub mod request {
-    use std::collections::HashMap;
+    use std::{
+        collections::HashMap,
+        time::{SystemTime, UNIX_EPOCH},
+    };
 
     use serde_bytes::ByteBuf;
     use serde_json::Value;
 
-    use crate::templates::*;
+    use super::{PROTOCOL, REV, VERSION};
+    use crate::{
+        address::PeerAddr,
+        command::Command,
+        peer_list::{Bucket, PackedPeers},
+        templates::*,
+    };
+
+    /// Handshake to send when dialing `remote`, advertising our fileserver
+    /// port and `peer_id`. The peer id is left out on onion connections so
+    /// that they can't be linked to our clearnet identity.
+    pub fn handshake<'a>(
+        remote: &PeerAddr,
+        fileserver_port: u16,
+        peer_id: &str,
+    ) -> (&'a str, Handshake) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        #[cfg(feature = "tor")]
+        let peer_id = if remote.is_onion() { "" } else { peer_id };
+        (
+            Handshake::NAME,
+            Handshake {
+                peer_id: peer_id.into(),
+                fileserver_port: fileserver_port.into(),
+                time,
+                crypt: None,
+                crypt_supported: vec![],
+                use_bin_type: true,
+                onion: None,
+                protocol: PROTOCOL.into(),
+                port_opened: None,
+                rev: REV,
+                target_address: Some(remote.host()),
+                version: VERSION.into(),
+            },
+        )
+    }
 
     ///Peer requests
     pub fn get_file<'a>(
@@ -15,7 +81,7 @@ pub mod request {
         read_bytes: Option<usize>,
     ) -> (&'a str, GetFile) {
         (
-            "getFile",
+            GetFile::NAME,
             GetFile {
                 site: site.into(),
                 inner_path: inner_path.into(),
@@ -34,7 +100,7 @@ pub mod request {
         read_bytes: usize,
     ) -> (&'a str, StreamFile) {
         (
-            "streamFile",
+            StreamFile::NAME,
             StreamFile {
                 site: site.into(),
                 inner_path: inner_path.into(),
@@ -47,12 +113,49 @@ pub mod request {
 
     pub fn pex<'a>(site: &'a str, need: usize) -> (&'a str, Pex) {
         (
-            "pex",
+            Pex::NAME,
             Pex {
                 site: site.into(),
                 peers: vec![],
                 peers_onion: Some(vec![]),
                 peers_ipv6: Some(vec![]),
+                peers_loki: None,
+                need,
+            },
+        )
+    }
+
+    /// Pex request offering up to `need` of `peers` in exchange. Peers in
+    /// the `exclude` buckets are left out, such as clearnet peers when
+    /// talking to an onion peer.
+    pub fn pex_with_peers<'a, 'p, I>(
+        site: &'a str,
+        need: usize,
+        peers: I,
+        exclude: &[Bucket],
+    ) -> (&'a str, Pex)
+    where
+        I: IntoIterator<Item = &'p PeerAddr>,
+    {
+        let mut packed = PackedPeers::default();
+        let mut count = 0;
+        for peer in peers {
+            if count == need {
+                break;
+            }
+            let included = Bucket::of(peer).is_some_and(|bucket| !exclude.contains(&bucket));
+            if included && packed.push(peer) {
+                count += 1;
+            }
+        }
+        (
+            Pex::NAME,
+            Pex {
+                site: site.into(),
+                peers: packed.ipv4,
+                peers_onion: Some(packed.onion),
+                peers_ipv6: Some(packed.ipv6),
+                peers_loki: Some(packed.loki).filter(|peers| !peers.is_empty()),
                 need,
             },
         )
@@ -66,7 +169,7 @@ pub mod request {
         modified: usize,
     ) -> (&'a str, Update) {
         (
-            "update",
+            Update::NAME,
             Update {
                 site: site.into(),
                 inner_path: inner_path.into(),
@@ -78,16 +181,16 @@ pub mod request {
     }
 
     pub fn list_modified<'a>(site: &'a str, since: usize) -> (&'a str, ListModified) {
-        ("listModified", ListModified { site: site.into(), since: since.into() })
+        (ListModified::NAME, ListModified { site: site.into(), since: since.into() })
     }
 
     pub fn get_hashfield<'a>(site: &'a str) -> (&'a str, GetHashfield) {
-        ("getHashfield", GetHashfield { site: site.into() })
+        (GetHashfield::NAME, GetHashfield { site: site.into() })
     }
 
     pub fn set_hashfield<'a>(site: &'a str, hashfield_raw: ByteBuf) -> (&'a str, SetHashfield) {
         (
-            "setHashfield",
+            SetHashfield::NAME,
             SetHashfield {
                 site: site.into(),
                 hashfield_raw,
@@ -96,16 +199,45 @@ pub mod request {
     }
 
     pub fn find_hash_ids<'a>(site: &'a str, hash_ids: Vec<usize>) -> (&'a str, FindHashIds) {
-        ("findHashIds", FindHashIds { site: site.into(), hash_ids })
+        (FindHashIds::NAME, FindHashIds { site: site.into(), hash_ids })
     }
 
     pub fn checkport<'a>(port: u16) -> (&'a str, Checkport) {
-        ("checkport", Checkport { port })
+        (Checkport::NAME, Checkport { port })
+    }
+
+    ///Tracker / AnnounceZero
+    #[allow(clippy::too_many_arguments)]
+    pub fn announce<'a>(
+        hashes: Vec<ByteBuf>,
+        port: u16,
+        need_types: Vec<String>,
+        need_num: usize,
+        add: Vec<String>,
+        delete: bool,
+        onions: Vec<String>,
+        onion_signs: HashMap<String, ByteBuf>,
+        onion_sign_this: &str,
+    ) -> (&'a str, Announce) {
+        (
+            Announce::NAME,
+            Announce {
+                hashes,
+                port,
+                need_types,
+                need_num,
+                add,
+                delete,
+                onions,
+                onion_signs,
+                onion_sign_this: onion_sign_this.into(),
+            },
+        )
     }
 
     ///Bigfile Plugin
     pub fn get_piece_fields<'a>(site: &'a str) -> (&'a str, GetPieceFields) {
-        ("getPieceFields", GetPieceFields { site: site.into() })
+        (GetPieceFields::NAME, GetPieceFields { site: site.into() })
     }
 
     pub fn set_piece_fields<'a>(
@@ -113,7 +245,7 @@ pub mod request {
         piecefields_packed: ByteBuf,
     ) -> (&'a str, SetPieceFields) {
         (
-            "setPieceFields",
+            SetPieceFields::NAME,
             SetPieceFields {
                 site: site.into(),
                 piecefields_packed,
@@ -155,6 +287,7 @@ pub mod response {
             peers,
             peers_ipv6,
             peers_onion,
+            peers_loki: vec![],
         }
     }
 
@@ -195,6 +328,14 @@ pub mod response {
         }
     }
 
+    ///Tracker / AnnounceZero
+    pub fn announce(peers: Vec<AnnouncedPeers>, onion_sign_this: &str) -> AnnounceResponse {
+        AnnounceResponse {
+            peers,
+            onion_sign_this: onion_sign_this.into(),
+        }
+    }
+
     ///Bigfile Plugin
     pub fn get_piece_fields(piecefields_packed: ByteBuf) -> GetPieceFieldsResponse {
         GetPieceFieldsResponse { piecefields_packed }
@@ -204,3 +345,69 @@ pub mod response {
         SetPieceFieldsResponse { ok }
     }
 }
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use crate::{address::PeerAddr, peer_list::Bucket};
+
+    #[test]
+    fn test_peer_id() {
+        let id = peer_id();
+        assert_eq!(id.len(), 20);
+        assert!(id.starts_with(PEER_ID_PREFIX));
+        assert!(id[8..].chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(id, peer_id());
+    }
+
+    #[test]
+    fn test_handshake() {
+        let remote = PeerAddr::parse("[::1]:15441").unwrap();
+        let (cmd, handshake) = request::handshake(&remote, 26552, "-DN0100-abcdefghijkl");
+        assert_eq!(cmd, "handshake");
+        assert_eq!(handshake.peer_id, "-DN0100-abcdefghijkl");
+        assert_eq!(handshake.fileserver_port, 26552);
+        assert!(handshake.time > 0);
+        assert!(handshake.use_bin_type);
+        assert_eq!(handshake.protocol, PROTOCOL);
+        assert_eq!(handshake.rev, REV);
+        assert_eq!(handshake.target_address.as_deref(), Some("::1"));
+    }
+
+    #[cfg(feature = "tor")]
+    #[test]
+    fn test_handshake_onion() {
+        let remote =
+            PeerAddr::parse("trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion:4321")
+                .unwrap();
+        let (_, handshake) = request::handshake(&remote, 26552, &peer_id());
+        assert!(handshake.peer_id.is_empty());
+        assert_eq!(
+            handshake.target_address.as_deref(),
+            Some("trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion")
+        );
+    }
+
+    #[test]
+    fn test_pex_with_peers() {
+        let peers: Vec<PeerAddr> = [
+            "127.0.0.1:4321",
+            "[::1]:4321",
+            "10.0.0.1:15441",
+            "10.0.0.2:15441",
+        ]
+        .iter()
+        .map(|address| PeerAddr::parse(*address).unwrap())
+        .collect();
+        let (_, pex) = request::pex_with_peers("1ADDR", 3, &peers, &[]);
+        assert_eq!(pex.peers.len(), 2);
+        assert_eq!(pex.peers_ipv6.as_ref().unwrap().len(), 1);
+        assert_eq!(pex.peers_loki, None);
+        assert_eq!(pex.need, 3);
+
+        let (_, pex) = request::pex_with_peers("1ADDR", 3, &peers, &[Bucket::Ipv4]);
+        assert!(pex.peers.is_empty());
+        assert_eq!(pex.peers_ipv6.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,237 @@
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use crate::{
+    address::PeerAddr,
+    builders::response,
+    connection::Connection,
+    error::Error,
+    external_ip::ExternalIp,
+    templates::{Checkport, CheckportResponse, Handshake},
+    transport::Connectors,
+    utils::Either,
+};
+
+/// How long a check may take to connect and get a handshake back, and how
+/// long a peer gets to answer our `checkport`.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Server side of `checkport`: dials back to the requester on the port it
+/// asked about and tells whether a handshake went through.
+#[derive(Clone)]
+pub struct PortChecker {
+    connectors: Connectors,
+    timeout: Duration,
+}
+
+impl PortChecker {
+    pub fn new(connectors: Connectors) -> PortChecker {
+        PortChecker {
+            connectors,
+            timeout: CHECK_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> PortChecker {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Answer `params` from `requester` by sending `handshake` to its
+    /// address on the requested port. `status` is `open` if the remote
+    /// answered it in time and `closed` otherwise.
+    pub async fn check(
+        &self,
+        requester: &PeerAddr,
+        params: &Checkport,
+        handshake: &Handshake,
+    ) -> CheckportResponse {
+        let target = requester.with_port(params.port);
+        let mut handshake = handshake.clone();
+        handshake.target_address = Some(target.host());
+        let open = matches!(
+            timeout(self.timeout, self.handshake(&target, &handshake)).await,
+            Ok(Ok(()))
+        );
+        let status = match open {
+            true => "open",
+            false => "closed",
+        };
+        response::checkport(status, &requester.host())
+    }
+
+    async fn handshake(&self, target: &PeerAddr, handshake: &Handshake) -> Result<(), Error> {
+        let connection = self.connectors.open(target).await?;
+        match connection.call(handshake).await? {
+            Either::Success(_) => Ok(()),
+            Either::Error(error) => Err(Error::Remote(error.error)),
+        }
+    }
+}
+
+impl Default for PortChecker {
+    fn default() -> PortChecker {
+        PortChecker::new(Connectors::default())
+    }
+}
+
+/// Answers collected by `ask_peers`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PortReport {
+    pub answers: Vec<(PeerAddr, CheckportResponse)>,
+}
+
+impl PortReport {
+    /// `Some(true)` if any peer got through to us, since a closed port
+    /// can't be reached by mistake, `Some(false)` if peers answered but
+    /// none did, and `None` without answers.
+    pub fn port_opened(&self) -> Option<bool> {
+        match self.answers.is_empty() {
+            true => None,
+            false => Some(
+                self.answers
+                    .iter()
+                    .any(|(_, answer)| answer.status == "open"),
+            ),
+        }
+    }
+
+    /// Count the `ip_external` of every answer towards our address.
+    pub fn vote(&self, external_ip: &mut ExternalIp) {
+        for (peer, answer) in &self.answers {
+            external_ip.vote(peer, &answer.ip_external);
+        }
+    }
+}
+
+/// Ask each of `peers` to check `port` and collect the answers that came
+/// back within `within`. Peers are asked concurrently; errors and
+/// timeouts leave a peer out of the report.
+pub async fn ask_peers(
+    peers: &[(PeerAddr, Connection)],
+    port: u16,
+    within: Duration,
+) -> PortReport {
+    let tasks: Vec<_> = peers
+        .iter()
+        .map(|(peer, connection)| {
+            let peer = peer.clone();
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                let answer = timeout(within, connection.call(&Checkport { port })).await;
+                match answer {
+                    Ok(Ok(Either::Success(answer))) => Some((peer, answer)),
+                    _ => None,
+                }
+            })
+        })
+        .collect();
+    let mut report = PortReport::default();
+    for task in tasks {
+        if let Ok(Some(answer)) = task.await {
+            report.answers.push(answer);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use crate::{
+        builders::request,
+        message::{RequestType, ResponseType},
+        server::{Handler, Server},
+    };
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Answers handshakes, and checkports by checking back.
+    struct Node {
+        checker: PortChecker,
+    }
+
+    #[async_trait::async_trait]
+    impl Handler for Node {
+        async fn handle(&self, peer: &PeerAddr, request: RequestType) -> ResponseType {
+            let (_, handshake) = request::handshake(peer, 0, "-DN0100-abcdefghijkl");
+            match request {
+                RequestType::Handshake(_) => ResponseType::Handshake(handshake),
+                RequestType::Checkport(params) => {
+                    ResponseType::Checkport(self.checker.check(peer, &params, &handshake).await)
+                }
+                _ => ResponseType::UnknownCmd,
+            }
+        }
+    }
+
+    async fn node() -> (PeerAddr, Connection) {
+        let checker = PortChecker::default().with_timeout(Duration::from_secs(2));
+        let server = Server::bind("127.0.0.1:0", Node { checker }).await.unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        (PeerAddr::from(address), Connection::new(reader, writer))
+    }
+
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let (address, _) = node().await;
+        let checker = PortChecker::default();
+        let requester = PeerAddr::parse("127.0.0.1:1").unwrap();
+        let (_, handshake) = request::handshake(&requester, 0, "-DN0100-abcdefghijkl");
+
+        let params = Checkport {
+            port: address.get_port(),
+        };
+        let answer = checker.check(&requester, &params, &handshake).await;
+        assert_eq!(answer.status, "open");
+        assert_eq!(answer.ip_external, "127.0.0.1");
+
+        let params = Checkport {
+            port: closed_port().await,
+        };
+        let answer = checker.check(&requester, &params, &handshake).await;
+        assert_eq!(answer.status, "closed");
+    }
+
+    #[tokio::test]
+    async fn test_check_silent() {
+        // Accepts the connection but never answers the handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let checker = PortChecker::default().with_timeout(Duration::from_millis(100));
+        let requester = PeerAddr::parse("127.0.0.1:1").unwrap();
+        let answer = checker
+            .check(&requester, &Checkport { port }, &Handshake::default())
+            .await;
+        assert_eq!(answer.status, "closed");
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_ask_peers() {
+        let peers = vec![node().await, node().await];
+        let open = peers[0].0.get_port();
+        let report = ask_peers(&peers, open, CHECK_TIMEOUT).await;
+        assert_eq!(report.answers.len(), 2);
+        assert_eq!(report.port_opened(), Some(true));
+        let report = ask_peers(&peers, closed_port().await, CHECK_TIMEOUT).await;
+        assert_eq!(report.port_opened(), Some(false));
+        assert_eq!(
+            ask_peers(&[], open, CHECK_TIMEOUT).await.port_opened(),
+            None
+        );
+
+        let mut external_ip = ExternalIp::new(1);
+        report.vote(&mut external_ip);
+        // Loopback answers don't count.
+        assert_eq!(external_ip.ipv4(), None);
+    }
+}
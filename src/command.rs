@@ -0,0 +1,90 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::templates::*;
+
+/// Links a request template to the command it is sent with and the
+/// template its response decodes into.
+/// ```
+/// use decentnet_protocol::{command::Command, templates::*};
+///
+/// assert_eq!(GetPieceFields::NAME, "getPieceFields");
+/// let response: <GetPieceFields as Command>::Response = GetPieceFieldsResponse {
+///     piecefields_packed: Default::default(),
+/// };
+/// ```
+pub trait Command: Serialize + DeserializeOwned {
+    const NAME: &'static str;
+    type Response: Serialize + DeserializeOwned;
+}
+
+impl Command for Handshake {
+    const NAME: &'static str = "handshake";
+    type Response = Handshake;
+}
+
+impl Command for Ping {
+    const NAME: &'static str = "ping";
+    type Response = PingResponse;
+}
+
+impl Command for GetFile {
+    const NAME: &'static str = "getFile";
+    type Response = GetFileResponse;
+}
+
+/// The raw body following the response is available from
+/// `Response::stream_body`.
+impl Command for StreamFile {
+    const NAME: &'static str = "streamFile";
+    type Response = StreamFileResponse;
+}
+
+impl Command for Pex {
+    const NAME: &'static str = "pex";
+    type Response = PexResponse;
+}
+
+impl Command for Update {
+    const NAME: &'static str = "update";
+    type Response = UpdateSiteResponse;
+}
+
+impl Command for ListModified {
+    const NAME: &'static str = "listModified";
+    type Response = ListModifiedResponse;
+}
+
+impl Command for GetHashfield {
+    const NAME: &'static str = "getHashfield";
+    type Response = GetHashfieldResponse;
+}
+
+impl Command for SetHashfield {
+    const NAME: &'static str = "setHashfield";
+    type Response = SetHashfieldResponse;
+}
+
+impl Command for FindHashIds {
+    const NAME: &'static str = "findHashIds";
+    type Response = FindHashIdsResponse;
+}
+
+impl Command for Checkport {
+    const NAME: &'static str = "checkport";
+    type Response = CheckportResponse;
+}
+
+impl Command for Announce {
+    const NAME: &'static str = "announce";
+    type Response = AnnounceResponse;
+}
+
+impl Command for GetPieceFields {
+    const NAME: &'static str = "getPieceFields";
+    type Response = GetPieceFieldsResponse;
+}
+
+impl Command for SetPieceFields {
+    const NAME: &'static str = "setPieceFields";
+    type Response = SetPieceFieldsResponse;
+}
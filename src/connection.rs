@@ -0,0 +1,455 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, Mutex,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    address::PeerAddr,
+    command::Command,
+    error::Error,
+    message::{
+        MessageReader, MessageWriter, Request, RequestType, Response, ResponseType, ZeroMessage,
+    },
+    templates::{ErrorResponse, StreamFile, StreamFileResponse},
+    transport::Connectors,
+    utils::Either,
+};
+
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+type SharedWriter = Arc<Mutex<MessageWriter<BoxedWriter>>>;
+type Pending = StdMutex<PendingRequests>;
+
+#[derive(Default)]
+struct PendingRequests {
+    requests: HashMap<usize, PendingRequest>,
+    /// Set once the reader stopped, after which no response can arrive.
+    closed: bool,
+}
+
+struct PendingRequest {
+    sender: oneshot::Sender<Response>,
+    /// Whether the response may be followed by a raw body to buffer.
+    stream: bool,
+}
+
+/// Number of incoming requests queued for `next_request`. Requests past
+/// that are answered with `TOO_MANY_REQUESTS` rather than holding up
+/// responses to our own requests.
+const INCOMING_BUFFER: usize = 64;
+
+/// Error sent back for requests that overflow the incoming queue.
+pub const TOO_MANY_REQUESTS: &str = "Too many requests";
+
+/// A multiplexed connection to a single peer.
+///
+/// Each outgoing request gets a fresh `req_id` and waits for the response
+/// whose `to` matches it, so any number of requests may be in flight over
+/// the same socket. Requests sent by the remote are queued and handed out
+/// by `next_request`; those that overflow the queue get an error response
+/// right away.
+///
+/// A background task owns the read half and is stopped once the last
+/// clone of the connection is dropped.
+#[derive(Clone)]
+pub struct Connection {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    writer: SharedWriter,
+    pending: Arc<Pending>,
+    next_req_id: AtomicUsize,
+    incoming: Mutex<mpsc::Receiver<Request>>,
+    reader_task: JoinHandle<()>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
+impl Connection {
+    /// Wrap both halves of a transport. Must be called from within a
+    /// tokio runtime.
+    pub fn new<R, W>(reader: R, writer: W) -> Connection
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let pending = Arc::new(Pending::default());
+        let writer: BoxedWriter = Box::new(writer);
+        let writer = Arc::new(Mutex::new(MessageWriter::new(writer)));
+        let (incoming_tx, incoming_rx) = mpsc::channel(INCOMING_BUFFER);
+        let reader_task = tokio::spawn(read_loop(
+            MessageReader::new(reader),
+            pending.clone(),
+            incoming_tx,
+            writer.clone(),
+        ));
+        Connection {
+            shared: Arc::new(Shared {
+                writer,
+                pending,
+                next_req_id: AtomicUsize::new(0),
+                incoming: Mutex::new(incoming_rx),
+                reader_task,
+            }),
+        }
+    }
+
+    /// Dial `address` with the connector `connectors` has for its kind
+    /// and wrap the resulting transport.
+    pub async fn connect(address: &PeerAddr, connectors: &Connectors) -> Result<Connection, Error> {
+        connectors.open(address).await
+    }
+
+    /// Send a request and wait for the response addressed to it.
+    pub async fn request(&self, cmd: &str, params: RequestType) -> Result<Response, Error> {
+        self.request_with(cmd, &params).await
+    }
+
+    /// Send command `C` and decode the response into `C::Response`.
+    ///
+    /// Works for any `Command`, including ones defined outside this crate.
+    pub async fn call<C: Command>(
+        &self,
+        params: &C,
+    ) -> Result<Either<C::Response, ErrorResponse>, Error> {
+        self.request_with(C::NAME, params).await?.decode_for::<C>()
+    }
+
+    async fn request_with<P: Serialize>(&self, cmd: &str, params: &P) -> Result<Response, Error> {
+        let req_id = self.shared.next_req_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        let request = PendingRequest {
+            sender: tx,
+            stream: cmd == StreamFile::NAME,
+        };
+        {
+            let mut pending = self.shared.pending.lock().unwrap();
+            if pending.closed {
+                return Err(Error::ConnectionClosed);
+            }
+            pending.requests.insert(req_id, request);
+        }
+        let guard = PendingGuard {
+            pending: &self.shared.pending,
+            req_id,
+        };
+        let mut writer = self.shared.writer.lock().await;
+        writer.send_request(cmd, req_id, params).await?;
+        drop(writer);
+        let response = rx.await.map_err(|_| Error::ConnectionClosed)?;
+        drop(guard);
+        Ok(response)
+    }
+
+    /// Answer a request received through `next_request`.
+    pub async fn respond(&self, to: usize, body: ResponseType) -> Result<(), Error> {
+        self.send(&ZeroMessage::response(to, body)).await
+    }
+
+    /// Answer a request for command `C`, which need not be part of
+    /// `ResponseType`.
+    pub async fn respond_command<C: Command>(
+        &self,
+        to: usize,
+        body: &C::Response,
+    ) -> Result<(), Error> {
+        self.shared
+            .writer
+            .lock()
+            .await
+            .send_response(to, body)
+            .await
+    }
+
+    /// Answer a `streamFile` request with its header and raw body.
+    pub async fn respond_stream_file<B: AsyncRead + Unpin>(
+        &self,
+        to: usize,
+        response: &StreamFileResponse,
+        body: B,
+    ) -> Result<(), Error> {
+        let mut writer = self.shared.writer.lock().await;
+        writer.send_stream_file(to, response, body).await
+    }
+
+    pub async fn send(&self, message: &ZeroMessage) -> Result<(), Error> {
+        self.shared.writer.lock().await.send(message).await
+    }
+
+    /// Next request sent by the remote, or `None` once the connection
+    /// is closed.
+    pub async fn next_request(&self) -> Option<Request> {
+        self.shared.incoming.lock().await.recv().await
+    }
+
+    /// Number of requests still waiting for a response.
+    pub fn pending_requests(&self) -> usize {
+        self.shared.pending.lock().unwrap().requests.len()
+    }
+}
+
+/// Removes the pending entry if the request future is dropped before
+/// its response arrives.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    req_id: usize,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().requests.remove(&self.req_id);
+    }
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(
+    mut reader: MessageReader<R>,
+    pending: Arc<Pending>,
+    incoming: mpsc::Sender<Request>,
+    writer: SharedWriter,
+) {
+    loop {
+        let message = match reader.next().await {
+            Ok(Some(message)) => message,
+            // A frame that does not decode has been consumed; keep going.
+            Err(Error::MsgpackDecode(_)) => continue,
+            Ok(None) | Err(_) => break,
+        };
+        match message {
+            ZeroMessage::Response(mut response) => {
+                let request = match pending.lock().unwrap().requests.remove(&response.to) {
+                    Some(request) => request,
+                    // Nobody is waiting; `next` skips any body unread.
+                    None => continue,
+                };
+                if request.stream && reader.stream_remaining() > 0 {
+                    match reader.read_stream_body().await {
+                        Ok(body) => response.set_stream_body(body),
+                        Err(_) => break,
+                    }
+                }
+                let _ = request.sender.send(response);
+            }
+            ZeroMessage::Request(request) => {
+                // Waiting for room would stop responses from being delivered,
+                // and so would waiting for the writer.
+                if let Err(TrySendError::Full(request)) = incoming.try_send(request) {
+                    let writer = writer.clone();
+                    tokio::spawn(async move {
+                        let error = ErrorResponse {
+                            error: TOO_MANY_REQUESTS.to_string(),
+                        };
+                        let mut writer = writer.lock().await;
+                        let _ = writer.send_response(request.req_id, &error).await;
+                    });
+                }
+            }
+        }
+    }
+    // Dropping the senders wakes every waiting request with `ConnectionClosed`,
+    // and later requests fail right away.
+    let mut pending = pending.lock().unwrap();
+    pending.closed = true;
+    pending.requests.clear();
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use crate::{
+        builders::request,
+        interface::Requestable,
+        templates::{GetFileResponse, Ping},
+    };
+    use serde_bytes::ByteBuf;
+    use tokio::io::AsyncWriteExt;
+
+    fn pair() -> (
+        Connection,
+        MessageReader<impl AsyncRead>,
+        MessageWriter<impl AsyncWrite>,
+    ) {
+        let (local, remote) = tokio::io::duplex(1024);
+        let (local_read, local_write) = tokio::io::split(local);
+        let (remote_read, remote_write) = tokio::io::split(remote);
+        (
+            Connection::new(local_read, local_write),
+            MessageReader::new(remote_read),
+            MessageWriter::new(remote_write),
+        )
+    }
+
+    fn get_file_response(location: usize) -> ResponseType {
+        ResponseType::GetFile(GetFileResponse {
+            body: ByteBuf::from(vec![0]),
+            location,
+            size: 1,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_responses_out_of_order() {
+        let (connection, mut reader, mut writer) = pair();
+        let remote = tokio::spawn(async move {
+            let first = reader.next().await.unwrap().unwrap().req_id().unwrap();
+            let second = reader.next().await.unwrap().unwrap().req_id().unwrap();
+            let response = ZeroMessage::response(second, get_file_response(second));
+            writer.send(&response).await.unwrap();
+            let response = ZeroMessage::response(first, get_file_response(first));
+            writer.send(&response).await.unwrap();
+        });
+
+        let (_, first) = request::get_file("1ADDR", "content.json", 1, 0, None);
+        let (_, second) = request::get_file("1ADDR", "index.html", 1, 0, None);
+        let (first, second) = tokio::join!(
+            connection.request("getFile", RequestType::GetFile(first)),
+            connection.request("getFile", RequestType::GetFile(second)),
+        );
+        let first: GetFileResponse = first.unwrap().body().unwrap();
+        let second: GetFileResponse = second.unwrap().body().unwrap();
+        assert_eq!(first.location, 0);
+        assert_eq!(second.location, 1);
+        assert_eq!(connection.pending_requests(), 0);
+        remote.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incoming_request() {
+        let (connection, _reader, mut writer) = pair();
+        let ping = ZeroMessage::request("ping", 7, RequestType::Ping(Ping()));
+        writer.send(&ping).await.unwrap();
+        let request = connection.next_request().await.unwrap();
+        assert_eq!(request.cmd, "ping");
+        assert_eq!(request.req_id, 7);
+    }
+
+    #[tokio::test]
+    async fn test_stream_file_response() {
+        let (connection, mut reader, mut writer) = pair();
+        tokio::spawn(async move {
+            let req_id = reader.next().await.unwrap().unwrap().req_id().unwrap();
+            let response = StreamFileResponse {
+                location: 0,
+                size: 4,
+                stream_bytes: 4,
+            };
+            writer
+                .send_stream_file(req_id, &response, &b"body"[..])
+                .await
+                .unwrap();
+        });
+        let (_, params) = request::stream_file("1ADDR", "content.json", 4, 0, 4);
+        let response = connection
+            .request("streamFile", RequestType::StreamFile(params))
+            .await
+            .unwrap();
+        assert_eq!(response.stream_bytes(), Some(4));
+        assert_eq!(response.stream_body().unwrap().as_slice(), b"body");
+    }
+
+    #[tokio::test]
+    async fn test_unrequested_stream_body() {
+        let (connection, mut reader, mut writer) = pair();
+        tokio::spawn(async move {
+            let req_id = reader.next().await.unwrap().unwrap().req_id().unwrap();
+            let response = StreamFileResponse {
+                location: 0,
+                size: 4,
+                stream_bytes: 4,
+            };
+            writer
+                .send_stream_file(req_id, &response, &b"body"[..])
+                .await
+                .unwrap();
+            let req_id = reader.next().await.unwrap().unwrap().req_id().unwrap();
+            let response = ZeroMessage::response(req_id, get_file_response(0));
+            writer.send(&response).await.unwrap();
+        });
+        // Only bodies answering `streamFile` are buffered; others are skipped.
+        let response = connection
+            .request("ping", RequestType::Ping(Ping()))
+            .await
+            .unwrap();
+        assert_eq!(response.stream_body(), None);
+        let response = connection
+            .request("ping", RequestType::Ping(Ping()))
+            .await
+            .unwrap();
+        assert!(response.body::<GetFileResponse>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed() {
+        let (connection, reader, writer) = pair();
+        let request = connection.request("ping", RequestType::Ping(Ping()));
+        let close = async move {
+            tokio::task::yield_now().await;
+            drop((reader, writer));
+        };
+        let (result, _) = tokio::join!(request, close);
+        assert!(matches!(result, Err(Error::ConnectionClosed)));
+        assert_eq!(connection.pending_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_after_close() {
+        let (connection, reader, writer) = pair();
+        writer.into_inner().shutdown().await.unwrap();
+        while connection.next_request().await.is_some() {}
+        let result = connection.request("ping", RequestType::Ping(Ping())).await;
+        assert!(matches!(result, Err(Error::ConnectionClosed)));
+        assert_eq!(connection.pending_requests(), 0);
+        drop(reader);
+    }
+
+    #[tokio::test]
+    async fn test_undrained_requests() {
+        let (connection, mut reader, mut writer) = pair();
+        let remote = tokio::spawn(async move {
+            for req_id in 0..INCOMING_BUFFER * 2 {
+                let ping = ZeroMessage::request("ping", req_id, RequestType::Ping(Ping()));
+                writer.send(&ping).await.unwrap();
+            }
+            // Every request past the queue is refused, our own request
+            // still gets through.
+            let (mut overflow, mut answered) = (0, false);
+            while overflow < INCOMING_BUFFER || !answered {
+                match reader.next().await.unwrap().unwrap() {
+                    ZeroMessage::Request(request) => {
+                        let response = ZeroMessage::response(request.req_id, get_file_response(0));
+                        writer.send(&response).await.unwrap();
+                        answered = true;
+                    }
+                    ZeroMessage::Response(response) => {
+                        let error: ErrorResponse = response.body().unwrap();
+                        assert_eq!(error.error, TOO_MANY_REQUESTS);
+                        overflow += 1;
+                    }
+                }
+            }
+        });
+        let response = connection
+            .request("getFile", RequestType::Ping(Ping()))
+            .await
+            .unwrap();
+        assert!(response.body::<GetFileResponse>().is_ok());
+        remote.await.unwrap();
+    }
+}
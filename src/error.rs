@@ -1,5 +1,7 @@
 use crate::address::AddressError;
 use crate::address::ParseError;
+#[cfg(feature = "templates")]
+use crate::session::HandshakeError;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -12,4 +14,53 @@ pub enum Error {
     AddressError(#[from] AddressError),
     #[error("Error decoding base64 `{0}`")]
     Base64Decode(#[from] base64::DecodeError),
+    #[error("Error decoding body: `{0}`")]
+    BodyDecode(#[from] serde::de::value::Error),
+    #[error("I/O error: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("Error decoding msgpack: `{0}`")]
+    MsgpackDecode(#[from] rmp_serde::decode::Error),
+    #[error("Error encoding msgpack: `{0}`")]
+    MsgpackEncode(#[from] rmp_serde::encode::Error),
+    #[error("Invalid msgpack marker `{0:#04x}`")]
+    InvalidMarker(u8),
+    #[error("Message of {0} bytes exceeds the maximum frame size")]
+    FrameTooLarge(usize),
+    #[error("Stream ended in the middle of a message")]
+    UnexpectedEof,
+    #[error("Stream body ended after {actual} of {expected} bytes")]
+    ShortStreamBody { expected: usize, actual: usize },
+    #[error("Connection closed")]
+    ConnectionClosed,
+    #[error("Remote peer returned an error: `{0}`")]
+    Remote(String),
+    #[error("Unknown command `{0}`")]
+    UnknownCmd(String),
+    #[error("Expected command `{expected}`, got `{actual}`")]
+    CmdMismatch { expected: String, actual: String },
+    #[error("Unexpected message during the handshake")]
+    UnexpectedMessage,
+    #[error("Unsupported crypt `{0}`")]
+    UnsupportedCrypt(String),
+    #[cfg(feature = "templates")]
+    #[error("Incompatible handshake: `{0}`")]
+    Handshake(#[from] HandshakeError),
+    #[cfg(feature = "tls")]
+    #[error("TLS error: `{0}`")]
+    Tls(#[from] rustls::Error),
+    #[cfg(feature = "tls")]
+    #[error("Error generating certificate: `{0}`")]
+    Certificate(#[from] rcgen::Error),
+    #[error("Invalid params for `{cmd}`: `{source}`")]
+    InvalidParams {
+        cmd: String,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error("Invalid response to `{cmd}`: `{source}`")]
+    InvalidResponse {
+        cmd: String,
+        #[source]
+        source: Box<Error>,
+    },
 }
@@ -0,0 +1,209 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::address::PeerAddr;
+#[cfg(feature = "templates")]
+use crate::session::Session;
+
+/// Number of agreeing peers ZeroNet waits for before trusting an address.
+pub const DEFAULT_QUORUM: usize = 3;
+
+/// Number of hosts whose votes are kept. Past that, the oldest vote is
+/// dropped, so the result follows the peers seen most recently.
+pub const MAX_VOTES: usize = 100;
+
+/// Works out our public addresses from the `target_ip` peers report in
+/// their handshakes.
+///
+/// Each remote host gets one vote, its latest, so a single peer can't
+/// settle the address by reconnecting. Private, loopback and other
+/// non-routable addresses are ignored: they only tell that the remote is on
+/// our network. Once an address has a quorum of votes and more than any
+/// other of its family, it is the one to announce and to check the port
+/// of. Only the latest `MAX_VOTES` hosts are remembered.
+#[derive(Debug, Clone)]
+pub struct ExternalIp {
+    quorum: usize,
+    votes: HashMap<String, IpAddr>,
+    /// Voters, oldest first.
+    order: VecDeque<String>,
+}
+
+impl ExternalIp {
+    pub fn new(quorum: usize) -> ExternalIp {
+        ExternalIp {
+            quorum,
+            votes: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Count `target_address`, as reported by `voter`. Returns whether
+    /// it was a public address and counted.
+    pub fn vote(&mut self, voter: &PeerAddr, target_address: &str) -> bool {
+        match target_address.parse::<IpAddr>() {
+            Ok(ip) if is_public(ip) => {
+                let host = voter.host();
+                if self.votes.insert(host.clone(), ip).is_some() {
+                    self.order.retain(|voter| *voter != host);
+                } else if self.order.len() == MAX_VOTES {
+                    if let Some(oldest) = self.order.pop_front() {
+                        self.votes.remove(&oldest);
+                    }
+                }
+                self.order.push_back(host);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Count the `target_address` of a negotiated session with `voter`.
+    #[cfg(feature = "templates")]
+    pub fn vote_session(&mut self, voter: &PeerAddr, session: &Session) -> bool {
+        match &session.target_address {
+            Some(target_address) => self.vote(voter, target_address),
+            None => false,
+        }
+    }
+
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        match self.settled(IpAddr::is_ipv4)? {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        match self.settled(IpAddr::is_ipv6)? {
+            IpAddr::V6(ip) => Some(ip),
+            IpAddr::V4(_) => None,
+        }
+    }
+
+    fn settled(&self, family: fn(&IpAddr) -> bool) -> Option<IpAddr> {
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in self.votes.values().filter(|ip| family(ip)) {
+            *counts.entry(*ip).or_default() += 1;
+        }
+        let (leader, votes) = counts.iter().max_by_key(|(_, votes)| **votes)?;
+        let tied = counts.values().filter(|count| *count == votes).count() > 1;
+        match *votes >= self.quorum && !tied {
+            true => Some(*leader),
+            false => None,
+        }
+    }
+}
+
+impl Default for ExternalIp {
+    fn default() -> ExternalIp {
+        ExternalIp::new(DEFAULT_QUORUM)
+    }
+}
+
+/// Whether `ip` could be reachable from the internet.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7.
+                || first & 0xfe00 == 0xfc00
+                // Link local, fe80::/10.
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+
+    fn voter(n: u8) -> PeerAddr {
+        PeerAddr::IPV4([198, 51, 100, n], 15441)
+    }
+
+    #[test]
+    fn test_settle() {
+        let mut external = ExternalIp::new(2);
+        assert!(external.vote(&voter(1), "93.184.216.34"));
+        assert_eq!(external.ipv4(), None);
+        // Reconnecting doesn't count twice.
+        external.vote(&voter(1).with_port(1234), "93.184.216.34");
+        assert_eq!(external.ipv4(), None);
+        external.vote(&voter(2), "93.184.216.34");
+        assert_eq!(external.ipv4(), Some(Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(external.ipv6(), None);
+
+        // A tie unsettles it until a majority is back.
+        external.vote(&voter(3), "93.184.216.35");
+        external.vote(&voter(4), "93.184.216.35");
+        assert_eq!(external.ipv4(), None);
+        external.vote(&voter(1), "93.184.216.35");
+        assert_eq!(external.ipv4(), Some(Ipv4Addr::new(93, 184, 216, 35)));
+    }
+
+    #[test]
+    fn test_max_votes() {
+        let mut external = ExternalIp::new(1);
+        external.vote(&voter(0), "93.184.216.34");
+        for n in 1..MAX_VOTES as u8 {
+            external.vote(&voter(n), "93.184.216.35");
+        }
+        // Voting again makes voter 0 the latest, voter 1 is dropped first.
+        external.vote(&voter(0), "93.184.216.34");
+        external.vote(&voter(MAX_VOTES as u8), "93.184.216.34");
+        assert_eq!(external.votes.len(), MAX_VOTES);
+        assert!(external.votes.contains_key(&voter(0).host()));
+        assert!(!external.votes.contains_key(&voter(1).host()));
+        assert_eq!(external.ipv4(), Some(Ipv4Addr::new(93, 184, 216, 35)));
+    }
+
+    #[test]
+    fn test_ignore_non_public() {
+        let mut external = ExternalIp::new(1);
+        for (n, ip) in [
+            "127.0.0.1",
+            "192.168.1.10",
+            "10.1.2.3",
+            "100.64.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.10",
+            "example.onion",
+            "",
+        ]
+        .iter()
+        .enumerate()
+        {
+            assert!(!external.vote(&voter(n as u8), ip), "counted {}", ip);
+        }
+        assert_eq!(external.ipv4(), None);
+        assert_eq!(external.ipv6(), None);
+
+        external.vote(&voter(0), "2001:4860:4860::8888");
+        assert_eq!(
+            external.ipv6(),
+            Some("2001:4860:4860::8888".parse().unwrap())
+        );
+    }
+}
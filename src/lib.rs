@@ -3,11 +3,31 @@ mod utils;
 pub mod address;
 #[cfg(feature = "builders")]
 pub mod builders;
+#[cfg(feature = "builders")]
+pub mod checkport;
+#[cfg(feature = "templates")]
+pub mod command;
+pub mod connection;
 pub mod error;
+pub mod external_ip;
 #[cfg(feature = "interface")]
 pub mod interface;
 pub mod message;
+#[cfg(all(feature = "interface", feature = "builders"))]
+pub mod peer;
+#[cfg(feature = "templates")]
+pub mod peer_list;
+#[cfg(feature = "i2p")]
+pub mod sam;
+#[cfg(feature = "interface")]
+pub mod server;
+#[cfg(feature = "templates")]
+pub mod session;
+pub mod socks;
 #[cfg(feature = "templates")]
 pub mod templates;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
 
 pub use utils::Either;
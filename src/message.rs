@@ -1,10 +1,31 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::OnceLock,
+    task::{ready, Context, Poll},
+};
 
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{
+    de::DeserializeOwned, ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer,
+};
 use serde_bytes::ByteBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 
-use crate::{error::Error, interface::Requestable, templates::*, utils::Value};
+use crate::{
+    command::Command,
+    error::Error,
+    interface::Requestable,
+    templates::*,
+    utils::{from_map, to_value, Either, Value},
+};
 
+/// Params of the requests this crate knows about.
+///
+/// Several commands share the same params shape, so incoming params are
+/// decoded by their `cmd` with `Request::params` rather than by guessing.
+/// `Deserialize` is kept for existing users, but picks the first variant
+/// whose shape matches.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum RequestType {
@@ -19,10 +40,33 @@ pub enum RequestType {
     SetHashfield(SetHashfield),
     FindHashIds(FindHashIds),
     Checkport(Checkport),
+    Announce(Announce),
     GetPieceFields(GetPieceFields),
     SetPieceFields(SetPieceFields),
 }
 
+impl RequestType {
+    /// Command these params are sent with.
+    pub fn cmd(&self) -> &'static str {
+        match self {
+            RequestType::Handshake(_) => "handshake",
+            RequestType::Ping(_) => "ping",
+            RequestType::GetFile(_) => "getFile",
+            RequestType::StreamFile(_) => "streamFile",
+            RequestType::Pex(_) => "pex",
+            RequestType::Update(_) => "update",
+            RequestType::ListModified(_) => "listModified",
+            RequestType::GetHashfield(_) => "getHashfield",
+            RequestType::SetHashfield(_) => "setHashfield",
+            RequestType::FindHashIds(_) => "findHashIds",
+            RequestType::Checkport(_) => "checkport",
+            RequestType::Announce(_) => "announce",
+            RequestType::GetPieceFields(_) => "getPieceFields",
+            RequestType::SetPieceFields(_) => "setPieceFields",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum ResponseType {
@@ -37,14 +81,32 @@ pub enum ResponseType {
     SetHashfield(SetHashfieldResponse),
     FindHashIds(FindHashIdsResponse),
     Checkport(CheckportResponse),
+    Announce(AnnounceResponse),
     GetPieceFields(GetPieceFieldsResponse),
     SetPieceFields(SetPieceFieldsResponse),
     Ok(OkResponse),
     Err(ErrorResponse),
+    #[serde(serialize_with = "invalid_request")]
     InvalidRequest,
+    #[serde(serialize_with = "unknown_cmd")]
     UnknownCmd,
 }
 
+/// ZeroNet answers requests it cannot handle with a plain `error` field.
+fn error_response<S: Serializer>(serializer: S, error: &str) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry("error", error)?;
+    map.end()
+}
+
+fn invalid_request<S: Serializer>(serializer: S) -> Result<S::Ok, S::Error> {
+    error_response(serializer, "Invalid request")
+}
+
+fn unknown_cmd<S: Serializer>(serializer: S) -> Result<S::Ok, S::Error> {
+    error_response(serializer, "Unknown cmd")
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Response {
     pub cmd: String,
@@ -54,28 +116,305 @@ pub struct Response {
     response: HashMap<String, Value>,
     #[serde(flatten, skip_deserializing)]
     body: Option<ResponseType>,
+    /// `response` of a response built locally from `body`.
+    #[serde(skip)]
+    built: Lazy<HashMap<String, Value>>,
+    #[serde(skip)]
+    stream_body: Option<ByteBuf>,
 }
 
 impl Response {
-    pub fn body<V: DeserializeOwned + Serialize>(&self) -> Result<V, Error> {
-        let result = serde_json::to_value(&self.response)?;
-        let result = serde_json::from_value(result)?;
-        Ok(result)
+    /// Decode the response body straight from its msgpack representation.
+    ///
+    /// Binary fields stay binary, and `V` may borrow strings and bytes from
+    /// the response instead of copying them:
+    /// ```
+    /// use decentnet_protocol::{builders::response, message::{ResponseType, ZeroMessage}};
+    /// use serde::Deserialize;
+    /// use serde_bytes::{ByteBuf, Bytes};
+    ///
+    /// #[derive(Deserialize)]
+    /// struct FileBody<'a> {
+    ///     #[serde(borrow)]
+    ///     body: &'a Bytes,
+    /// }
+    ///
+    /// let body = ByteBuf::from(vec![0, 159, 146, 150]);
+    /// let msg = ZeroMessage::response(0, ResponseType::GetFile(response::get_file(body, 4, 0)));
+    /// if let ZeroMessage::Response(response) = msg {
+    ///     let file: FileBody = response.body().unwrap();
+    ///     assert_eq!(file.body.as_ref(), [0, 159, 146, 150]);
+    /// }
+    /// ```
+    pub fn body<'a, V: Deserialize<'a>>(&'a self) -> Result<V, Error> {
+        Ok(from_map(self.map()?)?)
+    }
+
+    fn map(&self) -> Result<&HashMap<String, Value>, Error> {
+        let body = match &self.body {
+            Some(body) => body,
+            None => return Ok(&self.response),
+        };
+        self.built.get_or_try_init(|| {
+            let value = match body {
+                ResponseType::StreamFile(header, _) => to_value(header)?,
+                body => to_value(body)?,
+            };
+            Ok(match value {
+                Value::Object(map) => map,
+                _ => HashMap::new(),
+            })
+        })
+    }
+
+    /// Decode the response to a request of type `C`.
+    ///
+    /// Responses carry no command of their own, so the caller names the
+    /// request they answer. An `error` answer is returned as `Either::Error`.
+    /// ```
+    /// use decentnet_protocol::{message::ZeroMessage, templates::*, Either};
+    ///
+    /// let text = r#"{ "cmd": "response", "to": 0, "ok": "Updated" }"#;
+    /// let response = match serde_json::from_str(text).unwrap() {
+    ///     ZeroMessage::Response(response) => response,
+    ///     _ => unreachable!(),
+    /// };
+    /// let result = response.decode_for::<SetHashfield>().unwrap();
+    /// assert!(matches!(result, Either::Success(SetHashfieldResponse { .. })));
+    /// ```
+    pub fn decode_for<C: Command>(&self) -> Result<Either<C::Response, ErrorResponse>, Error> {
+        // Checked first since some responses have no required fields.
+        if let Ok(error) = self.body::<ErrorResponse>() {
+            return Ok(Either::Error(error));
+        }
+        self.body()
+            .map(Either::Success)
+            .map_err(|source| Error::InvalidResponse {
+                cmd: C::NAME.to_string(),
+                source: Box::new(source),
+            })
+    }
+
+    /// Decode the response to a request sent with `cmd`.
+    pub fn decode(&self, cmd: &str) -> Result<ResponseType, Error> {
+        match cmd {
+            "handshake" => self.decode_into::<Handshake>(ResponseType::Handshake),
+            "ping" => self.decode_into::<Ping>(ResponseType::Ping),
+            "getFile" => self.decode_into::<GetFile>(ResponseType::GetFile),
+            "streamFile" => Ok(match self.decode_for::<StreamFile>()? {
+                Either::Success(header) => {
+                    let body = self.stream_body().cloned().unwrap_or_default();
+                    ResponseType::StreamFile(header, body)
+                }
+                Either::Error(error) => ResponseType::Err(error),
+            }),
+            "pex" => self.decode_into::<Pex>(ResponseType::Pex),
+            "update" => self.decode_into::<Update>(ResponseType::UpdateSite),
+            "listModified" => self.decode_into::<ListModified>(ResponseType::ListModified),
+            "getHashfield" => self.decode_into::<GetHashfield>(ResponseType::GetHashfield),
+            "setHashfield" => self.decode_into::<SetHashfield>(ResponseType::SetHashfield),
+            "findHashIds" => self.decode_into::<FindHashIds>(ResponseType::FindHashIds),
+            "checkport" => self.decode_into::<Checkport>(ResponseType::Checkport),
+            "announce" => self.decode_into::<Announce>(ResponseType::Announce),
+            "getPieceFields" => self.decode_into::<GetPieceFields>(ResponseType::GetPieceFields),
+            "setPieceFields" => self.decode_into::<SetPieceFields>(ResponseType::SetPieceFields),
+            cmd => Err(Error::UnknownCmd(cmd.to_string())),
+        }
+    }
+
+    fn decode_into<C: Command>(
+        &self,
+        variant: fn(C::Response) -> ResponseType,
+    ) -> Result<ResponseType, Error> {
+        Ok(match self.decode_for::<C>()? {
+            Either::Success(response) => variant(response),
+            Either::Error(error) => ResponseType::Err(error),
+        })
+    }
+
+    /// Number of raw bytes that follow this response on the wire,
+    /// if it answers a `streamFile` request.
+    pub fn stream_bytes(&self) -> Option<usize> {
+        match self.map().ok()?.get("stream_bytes") {
+            Some(Value::Number(n)) => n.as_u64().map(|n| n as usize),
+            _ => None,
+        }
+    }
+
+    /// Raw body that followed a `streamFile` response, once it has been read.
+    pub fn stream_body(&self) -> Option<&ByteBuf> {
+        match &self.body {
+            Some(ResponseType::StreamFile(_, body)) => Some(body),
+            _ => self.stream_body.as_ref(),
+        }
+    }
+
+    /// Take the raw body out of the response, leaving `None` behind.
+    pub fn take_stream_body(&mut self) -> Option<ByteBuf> {
+        match &mut self.body {
+            Some(ResponseType::StreamFile(_, body)) => Some(std::mem::take(body)),
+            _ => self.stream_body.take(),
+        }
+    }
+
+    pub(crate) fn set_stream_body(&mut self, body: ByteBuf) {
+        self.stream_body = Some(body);
     }
 }
 
+/// Request frame for params that are not a `RequestType`.
+#[derive(Serialize)]
+struct RequestFrame<'a, P> {
+    cmd: &'a str,
+    req_id: usize,
+    params: &'a P,
+}
+
+/// Response frame for bodies that are not a `ResponseType`, such as the
+/// header of a `streamFile` response whose raw body is not part of the map.
+#[derive(Serialize)]
+struct ResponseFrame<'a, B> {
+    cmd: &'a str,
+    to: usize,
+    #[serde(flatten)]
+    body: &'a B,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Request {
     pub cmd: String,
     pub req_id: usize,
-    params: Option<RequestType>,
+    #[serde(default)]
+    params: Params,
+    /// `Value` form of typed params.
+    #[serde(skip)]
+    built: Lazy<Value>,
 }
 
 impl Request {
-    pub fn body<V: DeserializeOwned + Serialize>(&self) -> Result<V, Error> {
-        let result = serde_json::to_value(&self.params)?;
-        let result = serde_json::from_value(result)?;
-        Ok(result)
+    /// Decode the params straight from their msgpack representation,
+    /// borrowing from the request where `V` allows.
+    pub fn body<'a, V: Deserialize<'a>>(&'a self) -> Result<V, Error> {
+        let value = match &self.params {
+            Params::Typed(params) => self.built.get_or_try_init(|| to_value(params))?,
+            Params::Raw(value) => value,
+        };
+        Ok(V::deserialize(value)?)
+    }
+
+    /// Decode the params according to `cmd`.
+    ///
+    /// Fails with `Error::UnknownCmd` for commands without a template and
+    /// `Error::InvalidParams` when the params do not fit the command.
+    /// ```
+    /// use decentnet_protocol::message::{RequestType, ZeroMessage};
+    ///
+    /// let text = r#"{ "cmd": "getPieceFields", "req_id": 0, "params": { "site": "1ADDR" } }"#;
+    /// let request = match serde_json::from_str(text).unwrap() {
+    ///     ZeroMessage::Request(request) => request,
+    ///     _ => unreachable!(),
+    /// };
+    /// assert!(matches!(request.params().unwrap(), RequestType::GetPieceFields(_)));
+    /// ```
+    pub fn params(&self) -> Result<RequestType, Error> {
+        if let Params::Typed(params) = &self.params {
+            return Ok(params.clone());
+        }
+        let params = match self.cmd.as_str() {
+            "handshake" => RequestType::Handshake(self.decode_params()?),
+            // Ping carries no params; peers send an empty map.
+            "ping" => RequestType::Ping(Ping()),
+            "getFile" => RequestType::GetFile(self.decode_params()?),
+            "streamFile" => RequestType::StreamFile(self.decode_params()?),
+            "pex" => RequestType::Pex(self.decode_params()?),
+            "update" => RequestType::Update(self.decode_params()?),
+            "listModified" => RequestType::ListModified(self.decode_params()?),
+            "getHashfield" => RequestType::GetHashfield(self.decode_params()?),
+            "setHashfield" => RequestType::SetHashfield(self.decode_params()?),
+            "findHashIds" => RequestType::FindHashIds(self.decode_params()?),
+            "checkport" => RequestType::Checkport(self.decode_params()?),
+            "announce" => RequestType::Announce(self.decode_params()?),
+            "getPieceFields" => RequestType::GetPieceFields(self.decode_params()?),
+            "setPieceFields" => RequestType::SetPieceFields(self.decode_params()?),
+            cmd => return Err(Error::UnknownCmd(cmd.to_string())),
+        };
+        Ok(params)
+    }
+
+    /// Decode the params as command `C`, which need not be part of
+    /// `RequestType`. Fails with `Error::CmdMismatch` if the request was
+    /// sent with another command.
+    pub fn params_as<C: Command>(&self) -> Result<C, Error> {
+        if self.cmd != C::NAME {
+            return Err(Error::CmdMismatch {
+                expected: C::NAME.to_string(),
+                actual: self.cmd.clone(),
+            });
+        }
+        self.decode_params()
+    }
+
+    fn decode_params<V: DeserializeOwned>(&self) -> Result<V, Error> {
+        self.body().map_err(|source| Error::InvalidParams {
+            cmd: self.cmd.clone(),
+            source: Box::new(source),
+        })
+    }
+}
+
+/// Request params, either built locally or as received.
+///
+/// `RequestType` is untagged and cannot tell commands with the same shape
+/// apart, so received params are kept undecoded until the caller picks a
+/// type for them. Params built locally are sent as they are, and only
+/// converted to a `Value` when decoded, so both kinds decode the same way.
+#[derive(Debug, Clone, PartialEq)]
+enum Params {
+    Typed(RequestType),
+    Raw(Value),
+}
+
+impl Serialize for Params {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Params::Typed(params) => params.serialize(serializer),
+            Params::Raw(value) => value.serialize(serializer),
+        }
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params::Raw(Value::Null)
+    }
+}
+
+impl<'de> Deserialize<'de> for Params {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Value::deserialize(deserializer).map(Params::Raw)
+    }
+}
+
+/// Decoded form of a typed body, built the first time it is needed.
+///
+/// It only caches what the body already holds, so it is left out of
+/// comparisons.
+#[derive(Debug, Clone, Default)]
+struct Lazy<T>(OnceLock<T>);
+
+impl<T> Lazy<T> {
+    fn get_or_try_init(&self, init: impl FnOnce() -> Result<T, Error>) -> Result<&T, Error> {
+        if let Some(value) = self.0.get() {
+            return Ok(value);
+        }
+        let value = init()?;
+        Ok(self.0.get_or_init(|| value))
+    }
+}
+
+impl<T> PartialEq for Lazy<T> {
+    fn eq(&self, _: &Self) -> bool {
+        true
     }
 }
 
@@ -91,7 +430,8 @@ impl ZeroMessage {
         let request = Request {
             cmd: cmd.to_string(),
             req_id,
-            params: Some(body),
+            params: Params::Typed(body),
+            built: Lazy::default(),
         };
         ZeroMessage::Request(request)
     }
@@ -101,10 +441,12 @@ impl ZeroMessage {
             to,
             response: HashMap::new(),
             body: Some(body),
+            built: Lazy::default(),
+            stream_body: None,
         };
         ZeroMessage::Response(response)
     }
-    pub fn body<V: DeserializeOwned + Serialize>(self) -> Result<V, Error> {
+    pub fn body<V: DeserializeOwned>(self) -> Result<V, Error> {
         match self {
             ZeroMessage::Response(res) => res.body(),
             ZeroMessage::Request(req) => req.body(),
@@ -141,13 +483,363 @@ impl Requestable for ZeroMessage {
     }
 }
 
+/// Default upper bound for a single msgpack frame, matching the largest
+/// message a ZeroNet peer is expected to send.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Decodes a stream of concatenated msgpack maps into `ZeroMessage`s.
+///
+/// Peers do not length-prefix their messages, so the reader buffers
+/// incoming bytes until a complete msgpack value is available, then
+/// decodes exactly that frame. Any bytes past the frame are kept for
+/// the next call.
+///
+/// A `streamFile` response is followed by `stream_bytes` raw bytes. After
+/// such a response the reader switches to raw mode until the body has been
+/// consumed through `stream_body` or `read_stream_body`; calling `next`
+/// earlier discards it.
+pub struct MessageReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    scan: FrameScan,
+    max_frame_size: usize,
+    stream_remaining: usize,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R) -> MessageReader<R> {
+        MessageReader {
+            reader,
+            buffer: Vec::new(),
+            scan: FrameScan::default(),
+            max_frame_size: MAX_FRAME_SIZE,
+            stream_remaining: 0,
+        }
+    }
+
+    /// Change the largest frame the reader will buffer before giving up.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> MessageReader<R> {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Read the next message from the stream.
+    ///
+    /// Returns `Ok(None)` once the stream is closed on a frame boundary.
+    /// A well-formed frame that fails to decode into a message is still
+    /// consumed, so the reader stays in sync and the caller may keep
+    /// reading. `InvalidMarker` and `FrameTooLarge` are fatal: the frame
+    /// boundary is lost, every later call returns the same error and the
+    /// connection should be dropped.
+    pub async fn next(&mut self) -> Result<Option<ZeroMessage>, Error> {
+        self.skip_stream_body().await?;
+        loop {
+            if let Some(len) = self.scan.advance(&self.buffer)? {
+                if len > self.max_frame_size {
+                    return Err(Error::FrameTooLarge(len));
+                }
+                let result = rmp_serde::from_slice(&self.buffer[..len]);
+                self.buffer.drain(..len);
+                self.scan = FrameScan::default();
+                let message = result?;
+                if let ZeroMessage::Response(response) = &message {
+                    self.stream_remaining = response.stream_bytes().unwrap_or(0);
+                }
+                return Ok(Some(message));
+            }
+            if self.buffer.len() > self.max_frame_size {
+                return Err(Error::FrameTooLarge(self.buffer.len()));
+            }
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(Error::UnexpectedEof);
+            }
+        }
+    }
+
+    /// Raw body bytes of the last `streamFile` response not yet consumed.
+    pub fn stream_remaining(&self) -> usize {
+        self.stream_remaining
+    }
+
+    /// Bounded reader over the raw body of the last `streamFile` response.
+    pub fn stream_body(&mut self) -> StreamBody<'_, R> {
+        StreamBody { reader: self }
+    }
+
+    /// Read the whole raw body of the last `streamFile` response.
+    ///
+    /// Bodies larger than the maximum frame size are refused with
+    /// `FrameTooLarge`; read those through `stream_body` instead.
+    pub async fn read_stream_body(&mut self) -> Result<ByteBuf, Error> {
+        if self.stream_remaining > self.max_frame_size {
+            return Err(Error::FrameTooLarge(self.stream_remaining));
+        }
+        let mut body = Vec::with_capacity(self.stream_remaining);
+        self.stream_body().read_to_end(&mut body).await?;
+        Ok(ByteBuf::from(body))
+    }
+
+    async fn skip_stream_body(&mut self) -> Result<(), Error> {
+        tokio::io::copy(&mut self.stream_body(), &mut tokio::io::sink()).await?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Return the underlying reader along with any bytes that were read
+    /// from it but not yet decoded.
+    pub fn into_inner(self) -> (R, Vec<u8>) {
+        (self.reader, self.buffer)
+    }
+}
+
+/// Raw body of a `streamFile` response, see `MessageReader::stream_body`.
+///
+/// Reading stops after `stream_bytes` bytes; the stream ending before that
+/// is reported as `UnexpectedEof`.
+pub struct StreamBody<'a, R> {
+    reader: &'a mut MessageReader<R>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for StreamBody<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let reader = &mut *self.get_mut().reader;
+        let max = reader.stream_remaining.min(buf.remaining());
+        if max == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if !reader.buffer.is_empty() {
+            let len = max.min(reader.buffer.len());
+            buf.put_slice(&reader.buffer[..len]);
+            reader.buffer.drain(..len);
+            reader.stream_remaining -= len;
+            return Poll::Ready(Ok(()));
+        }
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+        ready!(Pin::new(&mut reader.reader).poll_read(cx, &mut limited))?;
+        let len = limited.filled().len();
+        if len == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        buf.advance(len);
+        reader.stream_remaining -= len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Encodes `ZeroMessage`s onto a stream, the counterpart of `MessageReader`.
+pub struct MessageWriter<W> {
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    pub fn new(writer: W) -> MessageWriter<W> {
+        MessageWriter { writer }
+    }
+
+    pub async fn send(&mut self, message: &ZeroMessage) -> Result<(), Error> {
+        self.write_frame(message).await
+    }
+
+    /// Send a request with any serializable params, such as a `Command`
+    /// unknown to `RequestType`.
+    pub async fn send_request<P: Serialize>(
+        &mut self,
+        cmd: &str,
+        req_id: usize,
+        params: &P,
+    ) -> Result<(), Error> {
+        let frame = RequestFrame {
+            cmd,
+            req_id,
+            params,
+        };
+        self.write_frame(&frame).await
+    }
+
+    /// Send a response with any serializable body, the counterpart of
+    /// `send_request`.
+    pub async fn send_response<B: Serialize>(&mut self, to: usize, body: &B) -> Result<(), Error> {
+        let frame = ResponseFrame {
+            cmd: "response",
+            to,
+            body,
+        };
+        self.write_frame(&frame).await
+    }
+
+    async fn write_frame<F: Serialize>(&mut self, frame: &F) -> Result<(), Error> {
+        let bytes = rmp_serde::to_vec_named(frame)?;
+        self.writer.write_all(&bytes).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Answer a `streamFile` request: write the response header, then
+    /// exactly `response.stream_bytes` raw bytes taken from `body`.
+    pub async fn send_stream_file<B: AsyncRead + Unpin>(
+        &mut self,
+        to: usize,
+        response: &StreamFileResponse,
+        body: B,
+    ) -> Result<(), Error> {
+        let header = ResponseFrame {
+            cmd: "response",
+            to,
+            body: response,
+        };
+        let bytes = rmp_serde::to_vec_named(&header)?;
+        self.writer.write_all(&bytes).await?;
+        let expected = response.stream_bytes as u64;
+        let written = tokio::io::copy(&mut body.take(expected), &mut self.writer).await?;
+        if written != expected {
+            return Err(Error::ShortStreamBody {
+                expected: response.stream_bytes,
+                actual: written as usize,
+            });
+        }
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Read a big-endian length of `size` bytes at `pos`.
+fn read_len(buf: &[u8], pos: usize, size: usize) -> Option<usize> {
+    let bytes = buf.get(pos..pos + size)?;
+    Some(
+        bytes
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize),
+    )
+}
+
+/// Walk over the msgpack value at the start of a buffer that grows between
+/// calls.
+///
+/// The position reached is kept, so every byte is looked at once however
+/// small the reads that fill the buffer. The walk is iterative so deeply
+/// nested input cannot overflow the stack.
+#[derive(Debug, Clone, Copy)]
+struct FrameScan {
+    /// Start of the next element, which may lie past the end of the buffer
+    /// while the payload of the previous one is still arriving.
+    pos: usize,
+    /// Elements left to walk, counting the items of open maps and arrays.
+    pending: usize,
+}
+
+impl Default for FrameScan {
+    fn default() -> FrameScan {
+        FrameScan { pos: 0, pending: 1 }
+    }
+}
+
+impl FrameScan {
+    /// Returns the length of the value once `buf` holds all of it, or
+    /// `None` if more bytes are needed.
+    fn advance(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+        loop {
+            if self.pos > buf.len() {
+                return Ok(None);
+            }
+            if self.pending == 0 {
+                return Ok(Some(self.pos));
+            }
+            let marker = match buf.get(self.pos) {
+                Some(marker) => *marker,
+                None => return Ok(None),
+            };
+            // Size of the length field, fixed payload size and item count per element.
+            let (len_size, fixed, items) = match marker {
+                0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (0, 0, 0),
+                0x80..=0x8f => (0, 0, (marker & 0x0f) as usize * 2),
+                0x90..=0x9f => (0, 0, (marker & 0x0f) as usize),
+                0xa0..=0xbf => (0, (marker & 0x1f) as usize, 0),
+                0xc4 | 0xd9 => (1, 0, 0),
+                0xc5 | 0xda => (2, 0, 0),
+                0xc6 | 0xdb => (4, 0, 0),
+                0xc7 => (1, 1, 0),
+                0xc8 => (2, 1, 0),
+                0xc9 => (4, 1, 0),
+                0xcc | 0xd0 => (0, 1, 0),
+                0xcd | 0xd1 => (0, 2, 0),
+                0xca | 0xce | 0xd2 => (0, 4, 0),
+                0xcb | 0xcf | 0xd3 => (0, 8, 0),
+                0xd4 => (0, 2, 0),
+                0xd5 => (0, 3, 0),
+                0xd6 => (0, 5, 0),
+                0xd7 => (0, 9, 0),
+                0xd8 => (0, 17, 0),
+                0xdc..=0xdf => {
+                    let size = if marker & 1 == 0 { 2 } else { 4 };
+                    let count = match read_len(buf, self.pos + 1, size) {
+                        Some(count) => count,
+                        None => return Ok(None),
+                    };
+                    let items = if marker >= 0xde {
+                        count.saturating_mul(2)
+                    } else {
+                        count
+                    };
+                    self.pos += 1 + size;
+                    self.pending = (self.pending - 1).saturating_add(items);
+                    continue;
+                }
+                0xc1 => return Err(Error::InvalidMarker(marker)),
+            };
+            let len = match len_size {
+                0 => 0,
+                size => match read_len(buf, self.pos + 1, size) {
+                    Some(len) => len,
+                    None => return Ok(None),
+                },
+            };
+            self.pos = (self.pos + 1 + len_size + fixed).saturating_add(len);
+            self.pending = (self.pending - 1).saturating_add(items);
+        }
+    }
+}
+
+/// Returns the length of the first complete msgpack value in `buf`,
+/// or `None` if more bytes are needed.
+#[cfg(test)]
+fn frame_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+    FrameScan::default().advance(buf)
+}
+
 #[cfg(test)]
 #[cfg_attr(tarpaulin, ignore)]
 mod tests {
+    use std::collections::HashMap;
+
+    use tokio::io::AsyncWriteExt;
+
     use crate::templates::*;
     use crate::{
+        error::Error,
         interface::Requestable,
-        message::{ResponseType, ZeroMessage},
+        message::{
+            frame_len, FrameScan, MessageReader, MessageWriter, Request, RequestType, Response,
+            ResponseType, ZeroMessage,
+        },
+        utils::Either,
     };
 
     #[test]
@@ -178,6 +870,24 @@ mod tests {
         assert_eq!(res.unwrap(), bytes);
     }
 
+    #[test]
+    fn test_stream_file_response_built() {
+        let header = StreamFileResponse {
+            location: 0,
+            size: 4,
+            stream_bytes: 4,
+        };
+        let body = ByteBuf::from(b"body".to_vec());
+        let msg = ZeroMessage::response(1, ResponseType::StreamFile(header.clone(), body));
+        let response = match msg {
+            ZeroMessage::Response(response) => response,
+            _ => panic!("not a response"),
+        };
+        assert_eq!(response.body::<StreamFileResponse>().unwrap(), header);
+        assert_eq!(response.stream_bytes(), Some(4));
+        assert_eq!(response.stream_body().unwrap().as_slice(), b"body");
+    }
+
     fn des(text: &str) -> Result<ZeroMessage, serde_json::error::Error> {
         serde_json::from_str(text)
     }
@@ -193,15 +903,7 @@ mod tests {
         rmp_serde::from_slice(&bytes).unwrap()
     }
 
-    use serde::{Deserialize, Serialize};
     use serde_bytes::ByteBuf;
-    #[derive(Deserialize, Serialize, Debug)]
-    struct AnnounceParams {
-        hashes: Vec<ByteBuf>,
-        port: usize,
-        need_types: Vec<String>,
-        delete: bool,
-    }
 
     #[test]
     fn test_announce() {
@@ -248,13 +950,395 @@ mod tests {
         ];
         assert_eq!(rmpd(bytes), msg);
 
-        //TODO! Uncomment when we have announce struct in templates
-        // let params: AnnounceParams = msg.body().unwrap();
-        // assert_eq!(params.port, 15441);
+        let params: Announce = msg.clone().body().unwrap();
+        assert_eq!(params.port, 15441);
+        assert_eq!(params.hashes.len(), 3);
+        assert_eq!(params.hashes[0].len(), 32);
+        assert_eq!(params.add, vec!["onion", "ipv4"]);
+        assert!(params.onion_signs.is_empty());
+        match msg {
+            ZeroMessage::Request(request) => {
+                assert!(matches!(request.params(), Ok(RequestType::Announce(_))))
+            }
+            _ => panic!("not a request"),
+        }
+    }
+
+    #[test]
+    fn test_announce_msgpack() {
+        let onion = "3g2upl4pq6kufc4m".to_string();
+        let mut onion_signs = HashMap::new();
+        onion_signs.insert(onion.clone(), ByteBuf::from(vec![7; 64]));
+        let params = Announce {
+            hashes: vec![ByteBuf::from(vec![1; 32])],
+            port: 15441,
+            need_types: vec!["ipv4".into(), "onion".into()],
+            need_num: 20,
+            add: vec!["onion".into()],
+            delete: false,
+            onions: vec![onion.clone()],
+            onion_signs,
+            onion_sign_this: "1599000000".into(),
+        };
+        let msg = ZeroMessage::request("announce", 1, RequestType::Announce(params.clone()));
+        let bytes = rmp_serde::to_vec_named(&msg).unwrap();
+        let msg: ZeroMessage = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(msg.body::<Announce>().unwrap(), params);
+
+        let msg = ZeroMessage::response(
+            1,
+            ResponseType::Announce(AnnounceResponse {
+                peers: vec![AnnouncedPeers::default()],
+                onion_sign_this: "1599000001".into(),
+            }),
+        );
+        let bytes = rmp_serde::to_vec_named(&msg).unwrap();
+        let msg: ZeroMessage = rmp_serde::from_slice(&bytes).unwrap();
+        let response: AnnounceResponse = msg.body().unwrap();
+        assert_eq!(response.onion_sign_this, "1599000001");
+        assert_eq!(response.peers, vec![AnnouncedPeers::default()]);
     }
 
     #[test]
-    fn test_announce_msgpack() {}
+    fn test_announce_response() {
+        let msg = response(
+            r#"
+		{
+			"cmd": "response",
+			"to": 0,
+			"peers": [
+				{ "ipv4": [[127, 0, 0, 1, 225, 16]], "onion": [] },
+				{}
+			]
+		}"#,
+        );
+        match msg.decode_for::<Announce>().unwrap() {
+            Either::Success(announce) => {
+                assert_eq!(announce.peers.len(), 2);
+                assert_eq!(
+                    announce.peers[0].ipv4[0].as_slice(),
+                    [127, 0, 0, 1, 225, 16]
+                );
+                assert!(announce.peers[1].ipv6.is_empty());
+            }
+            Either::Error(error) => panic!("unexpected error response {:?}", error),
+        }
+    }
+
+    fn request(text: &str) -> Request {
+        match des(text).unwrap() {
+            ZeroMessage::Request(request) => request,
+            _ => panic!("not a request"),
+        }
+    }
+
+    #[test]
+    fn test_params_by_cmd() {
+        let hashfield =
+            request(r#"{ "cmd": "getHashfield", "req_id": 0, "params": { "site": "1ADDR" } }"#);
+        let piecefields =
+            request(r#"{ "cmd": "getPieceFields", "req_id": 1, "params": { "site": "1ADDR" } }"#);
+        assert!(matches!(
+            hashfield.params().unwrap(),
+            RequestType::GetHashfield(_)
+        ));
+        assert!(matches!(
+            piecefields.params().unwrap(),
+            RequestType::GetPieceFields(_)
+        ));
+        let ping = request(r#"{ "cmd": "ping", "req_id": 2, "params": {} }"#);
+        assert_eq!(ping.params().unwrap().cmd(), "ping");
+    }
+
+    fn response(text: &str) -> Response {
+        match des(text).unwrap() {
+            ZeroMessage::Response(response) => response,
+            _ => panic!("not a response"),
+        }
+    }
+
+    #[test]
+    fn test_response_by_cmd() {
+        let ok = response(r#"{ "cmd": "response", "to": 0, "ok": "Updated" }"#);
+        assert!(matches!(
+            ok.decode("setHashfield").unwrap(),
+            ResponseType::SetHashfield(_)
+        ));
+        assert!(matches!(
+            ok.decode("update").unwrap(),
+            ResponseType::UpdateSite(_)
+        ));
+        let error = response(r#"{ "cmd": "response", "to": 0, "error": "Unknown site" }"#);
+        assert!(matches!(
+            error.decode("getPieceFields").unwrap(),
+            ResponseType::Err(_)
+        ));
+        assert!(matches!(
+            ok.decode("noSuchCommand"),
+            Err(Error::UnknownCmd(_))
+        ));
+    }
+
+    #[test]
+    fn test_response_for_command() {
+        let pex = response(
+            r#"{ "cmd": "response", "to": 0, "peers": [], "peers_ipv6": [], "peers_onion": [] }"#,
+        );
+        match pex.decode_for::<Pex>().unwrap() {
+            Either::Success(pex) => assert!(pex.peers.is_empty()),
+            Either::Error(error) => panic!("unexpected error response {:?}", error),
+        }
+        assert!(matches!(
+            pex.decode_for::<GetFile>(),
+            Err(Error::InvalidResponse { cmd, .. }) if cmd == "getFile"
+        ));
+    }
+
+    #[test]
+    fn test_body_keeps_bytes() {
+        let mut peers = HashMap::new();
+        peers.insert(7, vec![ByteBuf::from(vec![127, 0, 0, 1, 225, 16])]);
+        let body = FindHashIdsResponse {
+            peers,
+            peers_ipv6: HashMap::new(),
+            peers_onion: HashMap::new(),
+            my: vec![7],
+        };
+        let msg = ZeroMessage::response(0, ResponseType::FindHashIds(body.clone()));
+        let msg = rmpd(rmp_serde::to_vec_named(&msg).unwrap());
+        let msg = match msg {
+            ZeroMessage::Response(response) => response,
+            _ => panic!("not a response"),
+        };
+        assert_eq!(msg.body::<FindHashIdsResponse>().unwrap(), body);
+    }
+
+    #[test]
+    fn test_body_borrows() {
+        #[derive(serde::Deserialize)]
+        struct Borrowed<'a> {
+            #[serde(borrow)]
+            body: &'a serde_bytes::Bytes,
+        }
+
+        let body = ByteBuf::from(vec![0xff; 1024]);
+        let msg = ZeroMessage::response(
+            0,
+            ResponseType::GetFile(GetFileResponse {
+                body,
+                location: 0,
+                size: 1024,
+            }),
+        );
+        let msg = match rmpd(rmp_serde::to_vec_named(&msg).unwrap()) {
+            ZeroMessage::Response(response) => response,
+            _ => panic!("not a response"),
+        };
+        let borrowed: Borrowed = msg.body().unwrap();
+        assert_eq!(borrowed.body.len(), 1024);
+    }
+
+    #[test]
+    fn test_params_as_command() {
+        let msg = request(r#"{ "cmd": "checkport", "req_id": 0, "params": { "port": 15441 } }"#);
+        assert_eq!(msg.params_as::<Checkport>().unwrap().port, 15441);
+        assert!(matches!(
+            msg.params_as::<Pex>(),
+            Err(Error::CmdMismatch { expected, .. }) if expected == "pex"
+        ));
+    }
+
+    #[test]
+    fn test_request_type_by_shape() {
+        let text = r#"{ "peer_id": "", "fileserver_port": 0, "time": 1 }"#;
+        let params: RequestType = serde_json::from_str(text).unwrap();
+        assert!(matches!(params, RequestType::Handshake(_)));
+    }
+
+    #[test]
+    fn test_params_invalid() {
+        let msg = request(r#"{ "cmd": "getFile", "req_id": 0, "params": { "site": 1 } }"#);
+        assert!(matches!(
+            msg.params(),
+            Err(Error::InvalidParams { cmd, .. }) if cmd == "getFile"
+        ));
+        let msg = request(r#"{ "cmd": "noSuchCommand", "req_id": 0, "params": {} }"#);
+        assert!(matches!(msg.params(), Err(Error::UnknownCmd(cmd)) if cmd == "noSuchCommand"));
+    }
+
+    fn ping(req_id: usize) -> ZeroMessage {
+        let params = serde_json::json!({ "cmd": "ping", "req_id": req_id, "params": {} });
+        serde_json::from_value(params).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reader_several_messages_in_one_read() {
+        let mut bytes = rmps(&ping(0));
+        bytes.append(&mut rmps(&ping(1)));
+        bytes.append(&mut rmps(&ping(2)));
+        let mut reader = MessageReader::new(bytes.as_slice());
+        for req_id in 0..3 {
+            let msg = reader.next().await.unwrap().unwrap();
+            assert_eq!(msg.req_id(), Some(req_id));
+        }
+        assert!(reader.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reader_split_messages() {
+        let mut bytes = rmps(&ping(0));
+        bytes.append(&mut rmps(&ping(1)));
+        let (mut tx, rx) = tokio::io::duplex(3);
+        tokio::spawn(async move { tx.write_all(&bytes).await.unwrap() });
+        let mut reader = MessageReader::new(rx);
+        assert_eq!(reader.next().await.unwrap().unwrap(), ping(0));
+        assert_eq!(reader.next().await.unwrap().unwrap(), ping(1));
+        assert!(reader.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reader_truncated_message() {
+        let bytes = rmps(&ping(0));
+        let mut reader = MessageReader::new(&bytes[..bytes.len() - 1]);
+        assert!(matches!(reader.next().await, Err(Error::UnexpectedEof)));
+    }
+
+    #[tokio::test]
+    async fn test_reader_frame_too_large() {
+        let bytes = rmps(&ping(0));
+        let mut reader = MessageReader::new(bytes.as_slice()).with_max_frame_size(4);
+        assert!(matches!(reader.next().await, Err(Error::FrameTooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn test_stream_file_body() {
+        let response = StreamFileResponse {
+            location: 0,
+            size: 5,
+            stream_bytes: 5,
+        };
+        let mut writer = MessageWriter::new(Vec::new());
+        writer
+            .send_stream_file(1, &response, &b"hello"[..])
+            .await
+            .unwrap();
+        writer.send(&ping(2)).await.unwrap();
+        let bytes = writer.into_inner();
+
+        let mut reader = MessageReader::new(bytes.as_slice());
+        let msg = reader.next().await.unwrap().unwrap();
+        assert_eq!(msg.to(), Some(1));
+        assert_eq!(reader.stream_remaining(), 5);
+        assert_eq!(
+            reader.read_stream_body().await.unwrap(),
+            ByteBuf::from(&b"hello"[..])
+        );
+        assert_eq!(reader.stream_remaining(), 0);
+        assert_eq!(reader.next().await.unwrap().unwrap(), ping(2));
+    }
+
+    #[tokio::test]
+    async fn test_stream_file_body_too_large() {
+        let response = StreamFileResponse {
+            location: 0,
+            size: 1000,
+            stream_bytes: 1000,
+        };
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.send_response(1, &response).await.unwrap();
+        let bytes = writer.into_inner();
+
+        let mut reader = MessageReader::new(bytes.as_slice()).with_max_frame_size(100);
+        reader.next().await.unwrap().unwrap();
+        assert!(matches!(
+            reader.read_stream_body().await,
+            Err(Error::FrameTooLarge(1000))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_file_body_skipped() {
+        let response = StreamFileResponse {
+            location: 0,
+            size: 3,
+            stream_bytes: 3,
+        };
+        let mut writer = MessageWriter::new(Vec::new());
+        writer
+            .send_stream_file(1, &response, &b"abc"[..])
+            .await
+            .unwrap();
+        writer.send(&ping(2)).await.unwrap();
+        let bytes = writer.into_inner();
+
+        let (mut tx, rx) = tokio::io::duplex(2);
+        tokio::spawn(async move { tx.write_all(&bytes).await.unwrap() });
+        let mut reader = MessageReader::new(rx);
+        assert_eq!(reader.next().await.unwrap().unwrap().to(), Some(1));
+        assert_eq!(reader.next().await.unwrap().unwrap(), ping(2));
+    }
+
+    #[tokio::test]
+    async fn test_stream_file_short_body() {
+        let response = StreamFileResponse {
+            location: 0,
+            size: 10,
+            stream_bytes: 10,
+        };
+        let mut writer = MessageWriter::new(Vec::new());
+        let result = writer.send_stream_file(1, &response, &b"abc"[..]).await;
+        assert!(matches!(
+            result,
+            Err(Error::ShortStreamBody {
+                expected: 10,
+                actual: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_frame_len() {
+        let bytes = rmps(&ping(0));
+        for end in 0..bytes.len() {
+            assert_eq!(frame_len(&bytes[..end]).unwrap(), None);
+        }
+        assert_eq!(frame_len(&bytes).unwrap(), Some(bytes.len()));
+        assert!(matches!(
+            frame_len(&[0xc1]),
+            Err(Error::InvalidMarker(0xc1))
+        ));
+    }
+
+    #[test]
+    fn test_frame_scan_resumes() {
+        let bytes = rmps(&ping(0));
+        let mut scan = FrameScan::default();
+        for end in 0..bytes.len() {
+            assert_eq!(scan.advance(&bytes[..end]).unwrap(), None);
+        }
+        assert_eq!(scan.advance(&bytes).unwrap(), Some(bytes.len()));
+    }
+
+    #[tokio::test]
+    async fn test_reader_after_error() {
+        // A frame that isn't a message is skipped.
+        let mut bytes = vec![0x05];
+        bytes.append(&mut rmps(&ping(0)));
+        let mut reader = MessageReader::new(bytes.as_slice());
+        assert!(matches!(reader.next().await, Err(Error::MsgpackDecode(_))));
+        assert_eq!(reader.next().await.unwrap().unwrap(), ping(0));
+
+        // An invalid marker is fatal.
+        let mut bytes = vec![0xc1];
+        bytes.append(&mut rmps(&ping(0)));
+        let mut reader = MessageReader::new(bytes.as_slice());
+        for _ in 0..2 {
+            assert!(matches!(
+                reader.next().await,
+                Err(Error::InvalidMarker(0xc1))
+            ));
+        }
+    }
 
     #[test]
     fn test_get_file() {
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use serde_bytes::ByteBuf;
+use serde_json::Value;
+
+use crate::{
+    builders::request,
+    command::Command,
+    connection::Connection,
+    error::Error,
+    interface::RequestImpl,
+    message::{RequestType, Response},
+    templates::*,
+    utils::Either,
+};
+
+/// Number of peers asked for in a `pex` request, as ZeroNet does.
+pub const DEFAULT_PEX_NEED: usize = 5;
+
+/// Bytes asked for by `stream_file`, ZeroNet's default read size.
+pub const DEFAULT_READ_BYTES: usize = 512 * 1024;
+
+/// Client side of a peer connection.
+///
+/// Sends requests built with `builders::request` over a `Connection` and
+/// decodes the answers into their template responses. An `error` response
+/// from the remote is returned as `Either::Error` where the interface allows
+/// it and as `Error::Remote` otherwise.
+pub struct Peer {
+    connection: Connection,
+    handshake: Handshake,
+}
+
+impl Peer {
+    /// `handshake` is what we send to the remote when `handshake` is called.
+    pub fn new(connection: Connection, handshake: Handshake) -> Peer {
+        Peer {
+            connection,
+            handshake,
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Request a file with `streamFile`, returning the response header
+    /// together with the raw body that followed it.
+    pub async fn stream_file_body(
+        &mut self,
+        site: &str,
+        inner_path: &str,
+        file_size: usize,
+        location: usize,
+        read_bytes: usize,
+    ) -> Result<Either<(StreamFileResponse, ByteBuf), ErrorResponse>, Error> {
+        let (cmd, params) = request::stream_file(site, inner_path, file_size, location, read_bytes);
+        let response = self
+            .connection
+            .request(cmd, RequestType::StreamFile(params))
+            .await?;
+        split_stream_file(response)
+    }
+
+    async fn call<C: Command>(&self, params: &C) -> Result<C::Response, Error> {
+        match self.connection.call(params).await? {
+            Either::Success(body) => Ok(body),
+            Either::Error(error) => Err(Error::Remote(error.error)),
+        }
+    }
+}
+
+/// Header and raw body of a `streamFile` response. A header announcing
+/// `stream_bytes` without a body to go with it is a `ShortStreamBody`.
+fn split_stream_file(
+    mut response: Response,
+) -> Result<Either<(StreamFileResponse, ByteBuf), ErrorResponse>, Error> {
+    let header = match response.decode_for::<StreamFile>()? {
+        Either::Success(header) => header,
+        Either::Error(error) => return Ok(Either::Error(error)),
+    };
+    let body = match response.take_stream_body() {
+        Some(body) => body,
+        None if header.stream_bytes == 0 => ByteBuf::new(),
+        None => {
+            return Err(Error::ShortStreamBody {
+                expected: header.stream_bytes,
+                actual: 0,
+            })
+        }
+    };
+    Ok(Either::Success((header, body)))
+}
+
+#[async_trait::async_trait]
+impl RequestImpl for Peer {
+    type Error = Error;
+
+    async fn handshake(&mut self) -> Result<Handshake, Self::Error> {
+        self.call(&self.handshake).await
+    }
+
+    async fn ping(&mut self) -> Result<bool, Self::Error> {
+        let response = self.call(&Ping()).await?;
+        Ok(response.body == "Pong!")
+    }
+
+    async fn get_file(
+        &mut self,
+        site: &str,
+        inner_path: &str,
+        file_size: usize,
+        location: usize,
+        read_bytes: Option<usize>,
+    ) -> Result<Either<GetFileResponse, ErrorResponse>, Self::Error> {
+        let (_, params) = request::get_file(site, inner_path, file_size, location, read_bytes);
+        self.connection.call(&params).await
+    }
+
+    async fn stream_file(
+        &mut self,
+        site: &str,
+        inner_path: &str,
+    ) -> Result<Either<StreamFileResponse, ErrorResponse>, Self::Error> {
+        Ok(
+            match self
+                .stream_file_body(site, inner_path, 0, 0, DEFAULT_READ_BYTES)
+                .await?
+            {
+                Either::Success((header, _)) => Either::Success(header),
+                Either::Error(error) => Either::Error(error),
+            },
+        )
+    }
+
+    async fn list_modified(
+        &mut self,
+        site: &str,
+        since: usize,
+    ) -> Result<ListModifiedResponse, Self::Error> {
+        let (_, params) = request::list_modified(site, since);
+        self.call(&params).await
+    }
+
+    async fn pex(&mut self, site: &str) -> Result<PexResponse, Self::Error> {
+        let (_, params) = request::pex(site, DEFAULT_PEX_NEED);
+        self.call(&params).await
+    }
+
+    async fn update(
+        &mut self,
+        site: &str,
+        inner_path: &str,
+        body: ByteBuf,
+        diffs: HashMap<String, Vec<Value>>,
+        modified: usize,
+    ) -> Result<UpdateSiteResponse, Self::Error> {
+        let (_, params) = request::update_site(site, inner_path, body, diffs, modified);
+        self.call(&params).await
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use crate::{
+        builders::response,
+        message::{MessageReader, MessageWriter, ResponseType, ZeroMessage},
+    };
+
+    /// Connect a `Peer` to a remote that answers each request with the
+    /// response produced by `answer`.
+    fn peer<F>(answer: F) -> Peer
+    where
+        F: Fn(&str) -> ResponseType + Send + 'static,
+    {
+        let (local, remote) = tokio::io::duplex(1024);
+        let (local_read, local_write) = tokio::io::split(local);
+        let (remote_read, remote_write) = tokio::io::split(remote);
+        tokio::spawn(async move {
+            let mut reader = MessageReader::new(remote_read);
+            let mut writer = MessageWriter::new(remote_write);
+            while let Ok(Some(ZeroMessage::Request(request))) = reader.next().await {
+                let response = ZeroMessage::response(request.req_id, answer(&request.cmd));
+                writer.send(&response).await.unwrap();
+            }
+        });
+        Peer::new(
+            Connection::new(local_read, local_write),
+            Handshake::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_file() {
+        let mut peer =
+            peer(|_| ResponseType::GetFile(response::get_file(ByteBuf::from(&b"{}"[..]), 2, 2)));
+        let result = peer.get_file("1ADDR", "content.json", 2, 0, None).await;
+        match result.unwrap() {
+            Either::Success(file) => assert_eq!(file.body.as_slice(), b"{}"),
+            Either::Error(error) => panic!("unexpected error response {:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_file_error() {
+        let mut peer = peer(|_| {
+            ResponseType::Err(ErrorResponse {
+                error: "File read error".to_string(),
+            })
+        });
+        let result = peer.get_file("1ADDR", "missing.json", 0, 0, None).await;
+        assert!(matches!(result.unwrap(), Either::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_pex() {
+        let mut peer = peer(|cmd| {
+            assert_eq!(cmd, "pex");
+            let peers = vec![ByteBuf::from(vec![127, 0, 0, 1, 225, 16])];
+            ResponseType::Pex(response::pex(peers, vec![], vec![]))
+        });
+        let result = peer.pex("1ADDR").await.unwrap();
+        assert_eq!(result.peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_file_read_size() {
+        let (local, remote) = tokio::io::duplex(1024);
+        let (local_read, local_write) = tokio::io::split(local);
+        let (remote_read, remote_write) = tokio::io::split(remote);
+        tokio::spawn(async move {
+            let mut reader = MessageReader::new(remote_read);
+            let mut writer = MessageWriter::new(remote_write);
+            let request = match reader.next().await.unwrap().unwrap() {
+                ZeroMessage::Request(request) => request,
+                _ => panic!("not a request"),
+            };
+            let params: Value = request.body().unwrap();
+            assert_eq!(params["read_bytes"], DEFAULT_READ_BYTES);
+            let header = response::stream_file(2, 0, 2);
+            writer
+                .send_stream_file(request.req_id, &header, &b"{}"[..])
+                .await
+                .unwrap();
+        });
+        let mut peer = Peer::new(
+            Connection::new(local_read, local_write),
+            Handshake::default(),
+        );
+        let result = peer.stream_file("1ADDR", "content.json").await.unwrap();
+        assert!(matches!(result, Either::Success(header) if header.stream_bytes == 2));
+    }
+
+    fn response(text: &str) -> Response {
+        match serde_json::from_str(text).unwrap() {
+            ZeroMessage::Response(response) => response,
+            _ => panic!("not a response"),
+        }
+    }
+
+    #[test]
+    fn test_stream_file_without_body() {
+        let header =
+            r#"{ "cmd": "response", "to": 0, "location": 0, "size": 4, "stream_bytes": 4 }"#;
+        assert!(matches!(
+            split_stream_file(response(header)),
+            Err(Error::ShortStreamBody {
+                expected: 4,
+                actual: 0
+            })
+        ));
+
+        let header =
+            r#"{ "cmd": "response", "to": 0, "location": 0, "size": 0, "stream_bytes": 0 }"#;
+        match split_stream_file(response(header)).unwrap() {
+            Either::Success((header, body)) => {
+                assert_eq!(header.size, 0);
+                assert!(body.is_empty());
+            }
+            Either::Error(error) => panic!("unexpected error response {:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_error() {
+        let mut peer = peer(|_| {
+            ResponseType::Err(ErrorResponse {
+                error: "Unknown site".to_string(),
+            })
+        });
+        let result = peer.list_modified("1ADDR", 0).await;
+        assert!(matches!(result, Err(Error::Remote(error)) if error == "Unknown site"));
+    }
+}
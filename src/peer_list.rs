@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use serde_bytes::ByteBuf;
+
+use crate::{
+    address::{AddrKind, AddressError, PeerAddr},
+    templates::{FindHashIdsResponse, PexResponse},
+};
+
+/// Which of the per-network lists of pex and findHashIds a peer goes into.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum Bucket {
+    Ipv4,
+    Ipv6,
+    Onion,
+    Loki,
+}
+
+impl Bucket {
+    /// `None` for networks the protocol has no list for, such as I2P.
+    pub fn of(address: &PeerAddr) -> Option<Bucket> {
+        match address.kind() {
+            AddrKind::IPV4 => Some(Bucket::Ipv4),
+            AddrKind::IPV6 => Some(Bucket::Ipv6),
+            #[cfg(feature = "tor")]
+            AddrKind::OnionV2 | AddrKind::OnionV3 => Some(Bucket::Onion),
+            #[cfg(feature = "loki")]
+            AddrKind::Loki => Some(Bucket::Loki),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Unpack an entry of this bucket's list.
+    pub fn unpack(self, bytes: &[u8]) -> Result<PeerAddr, AddressError> {
+        let kinds: &[AddrKind] = match self {
+            Bucket::Ipv4 => &[AddrKind::IPV4],
+            Bucket::Ipv6 => &[AddrKind::IPV6],
+            #[cfg(feature = "tor")]
+            Bucket::Onion => &[AddrKind::OnionV2, AddrKind::OnionV3],
+            #[cfg(feature = "loki")]
+            Bucket::Loki => &[AddrKind::Loki],
+            #[allow(unreachable_patterns)]
+            _ => &[],
+        };
+        kinds
+            .iter()
+            .find_map(|kind| PeerAddr::unpack_as(bytes, *kind).ok())
+            .ok_or(AddressError::InvalidBytearray(bytes.len()))
+    }
+}
+
+/// Packed peers split by bucket.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PackedPeers {
+    pub ipv4: Vec<ByteBuf>,
+    pub ipv6: Vec<ByteBuf>,
+    pub onion: Vec<ByteBuf>,
+    pub loki: Vec<ByteBuf>,
+}
+
+impl PackedPeers {
+    /// Pack `address` into its bucket. Returns `false`, leaving the lists
+    /// untouched, if it has no bucket or does not pack.
+    pub fn push(&mut self, address: &PeerAddr) -> bool {
+        let bucket = match Bucket::of(address) {
+            Some(bucket) => bucket,
+            None => return false,
+        };
+        let packed = match address.pack() {
+            Ok(packed) => ByteBuf::from(packed),
+            Err(_) => return false,
+        };
+        self.bucket_mut(bucket).push(packed);
+        true
+    }
+
+    pub fn bucket_mut(&mut self, bucket: Bucket) -> &mut Vec<ByteBuf> {
+        match bucket {
+            Bucket::Ipv4 => &mut self.ipv4,
+            Bucket::Ipv6 => &mut self.ipv6,
+            Bucket::Onion => &mut self.onion,
+            Bucket::Loki => &mut self.loki,
+        }
+    }
+}
+
+impl<'a> FromIterator<&'a PeerAddr> for PackedPeers {
+    fn from_iter<I: IntoIterator<Item = &'a PeerAddr>>(peers: I) -> PackedPeers {
+        let mut packed = PackedPeers::default();
+        for peer in peers {
+            packed.push(peer);
+        }
+        packed
+    }
+}
+
+impl PexResponse {
+    /// Every peer of the response, skipping entries that don't unpack.
+    pub fn peer_addrs(&self) -> Vec<PeerAddr> {
+        self.unpack().filter_map(Result::ok).collect()
+    }
+
+    /// Every peer of the response, or the first entry that doesn't unpack.
+    pub fn try_peer_addrs(&self) -> Result<Vec<PeerAddr>, AddressError> {
+        self.unpack().collect()
+    }
+
+    fn unpack(&self) -> impl Iterator<Item = Result<PeerAddr, AddressError>> + '_ {
+        let lists = [
+            (Bucket::Ipv4, &self.peers[..]),
+            (Bucket::Ipv6, &self.peers_ipv6[..]),
+            (Bucket::Onion, &self.peers_onion[..]),
+            (Bucket::Loki, &self.peers_loki[..]),
+        ];
+        lists
+            .into_iter()
+            .flat_map(|(bucket, list)| list.iter().map(move |bytes| bucket.unpack(bytes)))
+    }
+
+    /// Pack `peers` into the lists of their networks. Peers that have no
+    /// list or do not pack are left out.
+    pub fn from_peer_addrs<'a, I: IntoIterator<Item = &'a PeerAddr>>(peers: I) -> PexResponse {
+        let packed: PackedPeers = peers.into_iter().collect();
+        PexResponse {
+            peers: packed.ipv4,
+            peers_ipv6: packed.ipv6,
+            peers_onion: packed.onion,
+            peers_loki: packed.loki,
+        }
+    }
+}
+
+impl FindHashIdsResponse {
+    /// Peers for each hash id, skipping entries that don't unpack or whose
+    /// hash id doesn't fit in two bytes.
+    pub fn peer_addrs(&self) -> HashMap<u16, Vec<PeerAddr>> {
+        let mut peers: HashMap<u16, Vec<PeerAddr>> = HashMap::new();
+        for (hash_id, address) in self.unpack().flatten() {
+            peers.entry(hash_id).or_default().push(address);
+        }
+        peers
+    }
+
+    /// Peers for each hash id, or the first entry that doesn't unpack or
+    /// whose hash id doesn't fit in two bytes.
+    pub fn try_peer_addrs(&self) -> Result<HashMap<u16, Vec<PeerAddr>>, AddressError> {
+        let mut peers: HashMap<u16, Vec<PeerAddr>> = HashMap::new();
+        for result in self.unpack() {
+            let (hash_id, address) = result?;
+            peers.entry(hash_id).or_default().push(address);
+        }
+        Ok(peers)
+    }
+
+    fn unpack(&self) -> impl Iterator<Item = Result<(u16, PeerAddr), AddressError>> + '_ {
+        let maps = [
+            (Bucket::Ipv4, &self.peers),
+            (Bucket::Ipv6, &self.peers_ipv6),
+            (Bucket::Onion, &self.peers_onion),
+        ];
+        maps.into_iter().flat_map(|(bucket, map)| {
+            map.iter().flat_map(move |(hash_id, list)| {
+                list.iter().map(move |bytes| {
+                    let hash_id = u16::try_from(*hash_id)
+                        .map_err(|_| AddressError::HashIdOutOfRange(*hash_id))?;
+                    Ok((hash_id, bucket.unpack(bytes)?))
+                })
+            })
+        })
+    }
+
+    /// Pack the peers of each hash id into the maps of their networks.
+    /// Peers that have no map, such as Lokinet ones, or do not pack are
+    /// left out.
+    pub fn from_peer_addrs(
+        peers: &HashMap<u16, Vec<PeerAddr>>,
+        my: Vec<usize>,
+    ) -> FindHashIdsResponse {
+        let mut response = FindHashIdsResponse {
+            peers: HashMap::new(),
+            peers_ipv6: HashMap::new(),
+            peers_onion: HashMap::new(),
+            my,
+        };
+        for (hash_id, addresses) in peers {
+            let packed: PackedPeers = addresses.iter().collect();
+            let lists = [
+                (&mut response.peers, packed.ipv4),
+                (&mut response.peers_ipv6, packed.ipv6),
+                (&mut response.peers_onion, packed.onion),
+            ];
+            for (map, list) in lists {
+                if !list.is_empty() {
+                    map.insert(*hash_id as usize, list);
+                }
+            }
+        }
+        response
+    }
+
+    /// Pack the peers of each hash id into the maps of their networks, or
+    /// fail on the first peer that has no map or does not pack.
+    pub fn try_from_peer_addrs(
+        peers: &HashMap<u16, Vec<PeerAddr>>,
+        my: Vec<usize>,
+    ) -> Result<FindHashIdsResponse, AddressError> {
+        let mut response = FindHashIdsResponse {
+            peers: HashMap::new(),
+            peers_ipv6: HashMap::new(),
+            peers_onion: HashMap::new(),
+            my,
+        };
+        for (hash_id, addresses) in peers {
+            for address in addresses {
+                let map = match Bucket::of(address) {
+                    Some(Bucket::Ipv4) => &mut response.peers,
+                    Some(Bucket::Ipv6) => &mut response.peers_ipv6,
+                    Some(Bucket::Onion) => &mut response.peers_onion,
+                    _ => return Err(AddressError::InvalidAddressType),
+                };
+                let packed = ByteBuf::from(address.pack()?);
+                map.entry(*hash_id as usize).or_default().push(packed);
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+
+    fn addr(address: &str) -> PeerAddr {
+        PeerAddr::parse(address).unwrap()
+    }
+
+    #[test]
+    fn test_pex_roundtrip() {
+        let peers = vec![
+            addr("127.0.0.1:4321"),
+            addr("[2001:db8::ff00:42:8329]:4321"),
+            addr("10.0.0.1:15441"),
+        ];
+        let response = PexResponse::from_peer_addrs(&peers);
+        assert_eq!(response.peers.len(), 2);
+        assert_eq!(response.peers_ipv6.len(), 1);
+        assert!(response.peers_onion.is_empty());
+
+        let mut unpacked = response.try_peer_addrs().unwrap();
+        let mut expected = peers.clone();
+        unpacked.sort_by_key(PeerAddr::to_string);
+        expected.sort_by_key(PeerAddr::to_string);
+        assert_eq!(unpacked, expected);
+    }
+
+    #[test]
+    fn test_pex_bad_entries() {
+        let mut response = PexResponse::from_peer_addrs(&[addr("127.0.0.1:4321")]);
+        // An IPv6 address in the IPv4 list and garbage in the IPv6 list.
+        response
+            .peers
+            .push(ByteBuf::from(addr("[::1]:1").pack().unwrap()));
+        response.peers_ipv6.push(ByteBuf::from(vec![1, 2, 3]));
+
+        assert_eq!(response.peer_addrs(), [addr("127.0.0.1:4321")]);
+        assert!(matches!(
+            response.try_peer_addrs(),
+            Err(AddressError::InvalidBytearray(18))
+        ));
+    }
+
+    #[cfg(feature = "tor")]
+    #[test]
+    fn test_pex_onion() {
+        let onion = addr("trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion:4321");
+        let response = PexResponse::from_peer_addrs(std::slice::from_ref(&onion));
+        assert_eq!(response.peers_onion.len(), 1);
+        assert_eq!(response.try_peer_addrs().unwrap(), [onion]);
+    }
+
+    #[test]
+    fn test_find_hash_ids_roundtrip() {
+        let mut peers = HashMap::new();
+        peers.insert(1u16, vec![addr("127.0.0.1:4321"), addr("[::1]:4321")]);
+        peers.insert(2u16, vec![addr("10.0.0.1:15441")]);
+        let response = FindHashIdsResponse::from_peer_addrs(&peers, vec![3]);
+        assert_eq!(response.peers[&1].len(), 1);
+        assert_eq!(response.peers_ipv6[&1].len(), 1);
+        assert!(!response.peers_ipv6.contains_key(&2));
+        assert_eq!(response.my, [3]);
+
+        let mut unpacked = response.try_peer_addrs().unwrap();
+        unpacked
+            .values_mut()
+            .for_each(|list| list.sort_by_key(PeerAddr::to_string));
+        peers
+            .values_mut()
+            .for_each(|list| list.sort_by_key(PeerAddr::to_string));
+        assert_eq!(unpacked, peers);
+        assert_eq!(
+            FindHashIdsResponse::try_from_peer_addrs(&peers, vec![3]).unwrap(),
+            response
+        );
+    }
+
+    #[test]
+    fn test_find_hash_ids_bad_entries() {
+        let mut peers = HashMap::new();
+        peers.insert(1u16, vec![addr("127.0.0.1:4321")]);
+        let mut response = FindHashIdsResponse::from_peer_addrs(&peers, vec![]);
+        let packed = ByteBuf::from(addr("10.0.0.1:15441").pack().unwrap());
+        response.peers.insert(70_000, vec![packed]);
+
+        assert_eq!(response.peer_addrs(), peers);
+        assert!(matches!(
+            response.try_peer_addrs(),
+            Err(AddressError::HashIdOutOfRange(70_000))
+        ));
+    }
+
+    #[cfg(feature = "loki")]
+    #[test]
+    fn test_find_hash_ids_loki() {
+        let mut peers = HashMap::new();
+        peers.insert(
+            1u16,
+            vec![addr(
+                "dw68y1xhptqbhcm5s8aaaip6dbopykagig5q5u1za4c7pzxto77y.loki:4321",
+            )],
+        );
+        let response = FindHashIdsResponse::from_peer_addrs(&peers, vec![]);
+        assert!(response.peer_addrs().is_empty());
+        assert!(matches!(
+            FindHashIdsResponse::try_from_peer_addrs(&peers, vec![]),
+            Err(AddressError::InvalidAddressType)
+        ));
+    }
+}
@@ -0,0 +1,295 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use koibumi_base32 as base32;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    address::{AddressError, AsyncReader, AsyncWriter, PeerAddr},
+    transport::Connector,
+};
+
+/// Address of the SAM bridge of a locally running I2P router.
+pub const SAM_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 7656);
+
+/// Longest reply line accepted from the bridge. Lines carrying a full
+/// destination are under a kilobyte.
+const MAX_LINE: usize = 8 * 1024;
+
+/// A SAM v3 stream session on an I2P router.
+///
+/// The session lives as long as this value: dropping it closes the control
+/// socket, and the router tears down the tunnels along with every stream
+/// opened through it.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), decentnet_protocol::address::AddressError> {
+/// use decentnet_protocol::{address::AddrKind, sam::SamSession, transport::Connectors};
+///
+/// let session = SamSession::create(SamSession::default_bridge(), "zeronet").await?;
+/// let mut connectors = Connectors::default();
+/// connectors.register(AddrKind::I2PB32, session);
+/// # Ok(())
+/// # }
+/// ```
+pub struct SamSession {
+    bridge: SocketAddr,
+    id: String,
+    _control: TcpStream,
+}
+
+impl SamSession {
+    pub fn default_bridge() -> SocketAddr {
+        SocketAddr::from(SAM_ADDR)
+    }
+
+    /// Create a session named `id` with a transient destination. `id` must
+    /// not be empty or contain whitespace or control characters.
+    pub async fn create<S: Into<String>>(
+        bridge: SocketAddr,
+        id: S,
+    ) -> Result<SamSession, AddressError> {
+        let id = id.into();
+        check_argument(&id)?;
+        let mut control = hello(bridge).await?;
+        let command = format!(
+            "SESSION CREATE STYLE=STREAM ID={} DESTINATION=TRANSIENT SIGNATURE_TYPE=EdDSA_SHA512_Ed25519\n",
+            id
+        );
+        request(&mut control, &command, "SESSION STATUS").await?;
+        Ok(SamSession {
+            bridge,
+            id,
+            _control: control,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Open a stream to `address`, which must be an I2P address.
+    pub async fn connect_addr(&self, address: &PeerAddr) -> Result<TcpStream, AddressError> {
+        let name = match address {
+            PeerAddr::I2PB32(name, _) => name,
+            _ => return Err(AddressError::InvalidAddressType),
+        };
+        // `PeerAddr::I2PB32` can be built without being parsed.
+        match base32::decode(name) {
+            Ok(hash) if hash.len() == 32 => {}
+            _ => return Err(AddressError::SamArgument(name.clone())),
+        }
+        let name = format!("{}.b32.i2p", name);
+        let mut stream = hello(self.bridge).await?;
+        let lookup = format!("NAMING LOOKUP NAME={}\n", name);
+        let reply = request(&mut stream, &lookup, "NAMING REPLY").await?;
+        let destination = reply.get("VALUE").ok_or(AddressError::SamProtocol)?;
+        let connect = format!(
+            "STREAM CONNECT ID={} DESTINATION={} SILENT=false\n",
+            self.id, destination
+        );
+        request(&mut stream, &connect, "STREAM STATUS").await?;
+        Ok(stream)
+    }
+}
+
+#[async_trait::async_trait]
+impl Connector for SamSession {
+    async fn connect(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(AsyncReader, AsyncWriter), AddressError> {
+        let (reader, writer) = self.connect_addr(address).await?.into_split();
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+/// Reject values that would split or end a command line.
+fn check_argument(value: &str) -> Result<(), AddressError> {
+    match value.is_empty() || value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        true => Err(AddressError::SamArgument(value.to_string())),
+        false => Ok(()),
+    }
+}
+
+async fn hello(bridge: SocketAddr) -> Result<TcpStream, AddressError> {
+    let mut stream = TcpStream::connect(bridge).await?;
+    request(
+        &mut stream,
+        "HELLO VERSION MIN=3.0 MAX=3.1\n",
+        "HELLO REPLY",
+    )
+    .await?;
+    Ok(stream)
+}
+
+/// Send `command` and parse the `KEY=VALUE` pairs of a successful reply
+/// starting with `expected`.
+async fn request(
+    stream: &mut TcpStream,
+    command: &str,
+    expected: &str,
+) -> Result<HashMap<String, String>, AddressError> {
+    stream.write_all(command.as_bytes()).await?;
+    let line = read_line(stream).await?;
+    let rest = line
+        .strip_prefix(expected)
+        .ok_or(AddressError::SamProtocol)?;
+    let reply = parse_pairs(rest);
+    match reply.get("RESULT").map(String::as_str) {
+        Some("OK") => Ok(reply),
+        Some(result) => Err(AddressError::SamError {
+            result: result.to_string(),
+            message: reply.get("MESSAGE").cloned().unwrap_or_default(),
+        }),
+        None => Err(AddressError::SamProtocol),
+    }
+}
+
+/// Read a single reply line. Bytes are taken one at a time so that
+/// nothing sent after it on a stream socket is lost.
+async fn read_line<R: AsyncRead + Unpin>(stream: &mut R) -> Result<String, AddressError> {
+    let mut line = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            byte => line.push(byte),
+        }
+        if line.len() > MAX_LINE {
+            return Err(AddressError::SamProtocol);
+        }
+    }
+    String::from_utf8(line).map_err(|_| AddressError::SamProtocol)
+}
+
+fn parse_pairs(line: &str) -> HashMap<String, String> {
+    let mut pairs = HashMap::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let (key, after) = match rest.split_once('=') {
+            Some(split) => split,
+            None => break,
+        };
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(' ').unwrap_or((after, "")),
+        };
+        pairs.insert(key.trim().to_string(), value.to_string());
+        rest = after.trim_start();
+    }
+    pairs
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const ADDRESS: &str = "udhdrtrcetjm5sxzskjyr5ztpeszydbh4dpl3pl4utgqqw2v4jna.b32.i2p:4321";
+
+    async fn expect(socket: &mut TcpStream, prefix: &str) -> String {
+        let line = read_line(socket).await.unwrap();
+        assert!(line.starts_with(prefix), "unexpected `{}`", line);
+        line
+    }
+
+    /// Answers a session and a single stream, then sends `hello` over it.
+    async fn mock_bridge(connect_result: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut control, _) = listener.accept().await.unwrap();
+            expect(&mut control, "HELLO VERSION").await;
+            control
+                .write_all(b"HELLO REPLY RESULT=OK VERSION=3.1\n")
+                .await
+                .unwrap();
+            expect(&mut control, "SESSION CREATE STYLE=STREAM ID=test").await;
+            control
+                .write_all(b"SESSION STATUS RESULT=OK DESTINATION=PRIVKEY\n")
+                .await
+                .unwrap();
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            expect(&mut stream, "HELLO VERSION").await;
+            stream
+                .write_all(b"HELLO REPLY RESULT=OK VERSION=3.1\n")
+                .await
+                .unwrap();
+            let lookup = expect(&mut stream, "NAMING LOOKUP").await;
+            assert!(lookup.ends_with(ADDRESS.trim_end_matches(":4321")));
+            stream
+                .write_all(b"NAMING REPLY RESULT=OK NAME=x.b32.i2p VALUE=DEST~\n")
+                .await
+                .unwrap();
+            expect(&mut stream, "STREAM CONNECT ID=test DESTINATION=DEST~").await;
+            let status = format!("STREAM STATUS {}\nhello", connect_result);
+            stream.write_all(status.as_bytes()).await.unwrap();
+            // Keep the control socket open until the stream is done.
+            let _ = stream.read_u8().await;
+            drop(control);
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let bridge = mock_bridge("RESULT=OK").await;
+        let session = SamSession::create(bridge, "test").await.unwrap();
+        let address = PeerAddr::parse(ADDRESS).unwrap();
+        let (mut reader, _writer) = Connector::connect(&session, &address).await.unwrap();
+        let mut greeting = [0u8; 5];
+        reader.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_stream_error() {
+        let bridge = mock_bridge("RESULT=CANT_REACH_PEER MESSAGE=\"no route\"").await;
+        let session = SamSession::create(bridge, "test").await.unwrap();
+        let address = PeerAddr::parse(ADDRESS).unwrap();
+        match session.connect_addr(&address).await {
+            Err(AddressError::SamError { result, message }) => {
+                assert_eq!(result, "CANT_REACH_PEER");
+                assert_eq!(message, "no route");
+            }
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_arguments() {
+        let bridge = SamSession::default_bridge();
+        for id in ["", "a b", "a\nSESSION", "tab\t"] {
+            assert!(matches!(
+                SamSession::create(bridge, id).await,
+                Err(AddressError::SamArgument(_))
+            ));
+        }
+
+        let session = SamSession::create(mock_bridge("RESULT=OK").await, "test")
+            .await
+            .unwrap();
+        let address = PeerAddr::I2PB32("x.b32.i2p DESTINATION=evil".to_string(), 4321);
+        assert!(matches!(
+            session.connect_addr(&address).await,
+            Err(AddressError::SamArgument(_))
+        ));
+        let address = PeerAddr::I2PB32("1".repeat(52), 4321);
+        assert!(matches!(
+            session.connect_addr(&address).await,
+            Err(AddressError::SamArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_pairs() {
+        let pairs = parse_pairs(" RESULT=I2P_ERROR MESSAGE=\"it broke\" VERSION=3.1");
+        assert_eq!(pairs["RESULT"], "I2P_ERROR");
+        assert_eq!(pairs["MESSAGE"], "it broke");
+        assert_eq!(pairs["VERSION"], "3.1");
+    }
+}
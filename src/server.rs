@@ -0,0 +1,373 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    sync::Semaphore,
+};
+
+use crate::{
+    address::PeerAddr,
+    connection::Connection,
+    error::Error,
+    message::{Request, RequestType, ResponseType},
+};
+
+/// Requests of a single connection handled at the same time. Further
+/// requests wait in the connection's queue for one of them to finish, and
+/// are refused with `TOO_MANY_REQUESTS` once that is full.
+pub const MAX_CONCURRENT_REQUESTS: usize = 16;
+
+/// Pause after an accept error that isn't about a single connection, such
+/// as running out of file descriptors, before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Answers the requests of connected peers.
+///
+/// `handle` receives requests whose params were decoded according to their
+/// `cmd`; requests the dispatcher could not decode never reach it. Returning
+/// `ResponseType::StreamFile` sends the header followed by the raw body.
+#[async_trait::async_trait]
+pub trait Handler: Send + Sync + 'static {
+    async fn handle(&self, peer: &PeerAddr, request: RequestType) -> ResponseType;
+
+    /// Called for commands this crate has no template for.
+    ///
+    /// Plugins decode their own commands with `Request::params_as` and answer
+    /// with `Connection::respond_command`; anything else is `UnknownCmd`.
+    async fn handle_unknown(
+        &self,
+        _peer: &PeerAddr,
+        request: &Request,
+        connection: &Connection,
+    ) -> Result<(), Error> {
+        connection
+            .respond(request.req_id, ResponseType::UnknownCmd)
+            .await
+    }
+}
+
+/// Accepts TCP connections and serves each of them with a shared handler.
+pub struct Server<H> {
+    listener: TcpListener,
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Server<H> {
+    pub async fn bind<A: ToSocketAddrs>(address: A, handler: H) -> Result<Server<H>, Error> {
+        let listener = TcpListener::bind(address).await?;
+        Ok(Server {
+            listener,
+            handler: Arc::new(handler),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections, serving each one on its own task.
+    ///
+    /// Errors accepting a connection don't stop the server: it moves on to
+    /// the next one, after a short pause for errors such as running out of
+    /// file descriptors.
+    pub async fn run(self) -> Result<(), Error> {
+        loop {
+            let (socket, address) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) if is_connection_error(&error) => continue,
+                Err(_) => {
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let (reader, writer) = socket.into_split();
+            let connection = Connection::new(reader, writer);
+            tokio::spawn(serve_connection(
+                connection,
+                PeerAddr::from(address),
+                self.handler.clone(),
+            ));
+        }
+    }
+}
+
+/// Errors that only concern the connection being accepted.
+fn is_connection_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Dispatch every request received on `connection` to `handler` until the
+/// remote closes it. Up to `MAX_CONCURRENT_REQUESTS` requests are handled
+/// concurrently.
+pub async fn serve_connection<H: Handler>(connection: Connection, peer: PeerAddr, handler: Arc<H>) {
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+    while let Some(request) = connection.next_request().await {
+        let permit = match permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+        let connection = connection.clone();
+        let peer = peer.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let to = request.req_id;
+            let response = match request.params() {
+                Ok(params) => handler.handle(&peer, params).await,
+                Err(Error::UnknownCmd(_)) => {
+                    let _ = handler.handle_unknown(&peer, &request, &connection).await;
+                    return;
+                }
+                Err(_) => ResponseType::InvalidRequest,
+            };
+            let result = match response {
+                ResponseType::StreamFile(header, body) => {
+                    connection
+                        .respond_stream_file(to, &header, body.as_slice())
+                        .await
+                }
+                response => connection.respond(to, response).await,
+            };
+            // The remote hung up; the outer loop ends on its own.
+            let _ = result;
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::Command,
+        connection::TOO_MANY_REQUESTS,
+        interface::{RequestImpl, Requestable},
+        message::{MessageReader, MessageWriter, ZeroMessage},
+        peer::Peer,
+        templates::*,
+        utils::Either,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_bytes::ByteBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpStream;
+
+    struct FileServer;
+
+    #[async_trait::async_trait]
+    impl Handler for FileServer {
+        async fn handle(&self, _peer: &PeerAddr, request: RequestType) -> ResponseType {
+            match request {
+                RequestType::Ping(_) => ResponseType::Ping(PingResponse {
+                    body: "Pong!".to_string(),
+                }),
+                RequestType::GetFile(params) => ResponseType::GetFile(GetFileResponse {
+                    body: ByteBuf::from(params.inner_path.into_bytes()),
+                    location: params.location,
+                    size: 12,
+                }),
+                RequestType::StreamFile(params) => ResponseType::StreamFile(
+                    StreamFileResponse {
+                        location: params.location,
+                        size: 4,
+                        stream_bytes: 4,
+                    },
+                    ByteBuf::from(&b"body"[..]),
+                ),
+                _ => ResponseType::UnknownCmd,
+            }
+        }
+    }
+
+    async fn connect() -> Connection {
+        let server = Server::bind("127.0.0.1:0", FileServer).await.unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        Connection::new(reader, writer)
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let mut peer = Peer::new(connect().await, Handshake::default());
+        assert!(peer.ping().await.unwrap());
+        let file = peer.get_file("1ADDR", "content.json", 12, 0, None).await;
+        match file.unwrap() {
+            Either::Success(file) => assert_eq!(file.body.as_slice(), b"content.json"),
+            Either::Error(error) => panic!("unexpected error response {:?}", error),
+        }
+        let file = peer
+            .stream_file_body("1ADDR", "content.json", 4, 0, 4)
+            .await;
+        match file.unwrap() {
+            Either::Success((header, body)) => {
+                assert_eq!(header.stream_bytes, 4);
+                assert_eq!(body.as_slice(), b"body");
+            }
+            Either::Error(error) => panic!("unexpected error response {:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_cmd() {
+        let connection = connect().await;
+        let params = RequestType::Checkport(Checkport { port: 15441 });
+        let response = connection.request("noSuchCommand", params).await.unwrap();
+        let error: ErrorResponse = response.body().unwrap();
+        assert_eq!(error.error, "Unknown cmd");
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Echo {
+        text: String,
+    }
+
+    impl Command for Echo {
+        const NAME: &'static str = "echo";
+        type Response = Echo;
+    }
+
+    struct EchoPlugin;
+
+    #[async_trait::async_trait]
+    impl Handler for EchoPlugin {
+        async fn handle(&self, _peer: &PeerAddr, _request: RequestType) -> ResponseType {
+            ResponseType::UnknownCmd
+        }
+
+        async fn handle_unknown(
+            &self,
+            _peer: &PeerAddr,
+            request: &Request,
+            connection: &Connection,
+        ) -> Result<(), Error> {
+            match request.params_as::<Echo>() {
+                Ok(echo) => {
+                    connection
+                        .respond_command::<Echo>(request.req_id, &echo)
+                        .await
+                }
+                Err(_) => {
+                    connection
+                        .respond(request.req_id, ResponseType::UnknownCmd)
+                        .await
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugin_command() {
+        let server = Server::bind("127.0.0.1:0", EchoPlugin).await.unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let connection = Connection::new(reader, writer);
+        let echo = Echo {
+            text: "hello".to_string(),
+        };
+        match connection.call(&echo).await.unwrap() {
+            Either::Success(echo) => assert_eq!(echo.text, "hello"),
+            Either::Error(error) => panic!("unexpected error response {:?}", error),
+        }
+    }
+
+    /// Records how many pings it is answering at once.
+    #[derive(Default)]
+    struct SlowPing {
+        running: AtomicUsize,
+        most: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Handler for Arc<SlowPing> {
+        async fn handle(&self, _peer: &PeerAddr, _request: RequestType) -> ResponseType {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            ResponseType::Ping(PingResponse {
+                body: "Pong!".to_string(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let handler = Arc::new(SlowPing::default());
+        let server = Server::bind("127.0.0.1:0", handler.clone()).await.unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let connection = Connection::new(reader, writer);
+        let pings = (0..MAX_CONCURRENT_REQUESTS * 2).map(|_| {
+            let connection = connection.clone();
+            tokio::spawn(async move { connection.call(&Ping()).await.unwrap() })
+        });
+        for ping in pings.collect::<Vec<_>>() {
+            ping.await.unwrap();
+        }
+        let most = handler.most.load(Ordering::SeqCst);
+        assert!(
+            most > 1 && most <= MAX_CONCURRENT_REQUESTS,
+            "{} at once",
+            most
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flood() {
+        let server = Server::bind("127.0.0.1:0", Arc::new(SlowPing::default()))
+            .await
+            .unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut reader = MessageReader::new(reader);
+        let mut writer = MessageWriter::new(writer);
+        let count = 200;
+        for req_id in 0..count {
+            let ping = ZeroMessage::request("ping", req_id, RequestType::Ping(Ping()));
+            writer.send(&ping).await.unwrap();
+        }
+
+        let mut answered = vec![false; count];
+        let mut refused = 0;
+        for _ in 0..count {
+            let response = match reader.next().await.unwrap().unwrap() {
+                ZeroMessage::Response(response) => response,
+                _ => panic!("not a response"),
+            };
+            assert!(!answered[response.to], "answered twice");
+            answered[response.to] = true;
+            if let Ok(error) = response.body::<ErrorResponse>() {
+                assert_eq!(error.error, TOO_MANY_REQUESTS);
+                refused += 1;
+            }
+        }
+        assert!(refused > 0 && refused < count, "{} refused", refused);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request() {
+        let server = Server::bind("127.0.0.1:0", FileServer).await.unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut reader = MessageReader::new(reader);
+        let mut writer = MessageWriter::new(writer);
+        let message: ZeroMessage =
+            serde_json::from_str(r#"{ "cmd": "getFile", "req_id": 3, "params": { "site": 1 } }"#)
+                .unwrap();
+        writer.send(&message).await.unwrap();
+        let response = reader.next().await.unwrap().unwrap();
+        assert_eq!(response.to(), Some(3));
+        let error: ErrorResponse = response.body().unwrap();
+        assert_eq!(error.error, "Invalid request");
+    }
+}
@@ -0,0 +1,158 @@
+use thiserror::Error;
+
+use crate::templates::Handshake;
+
+/// Protocol revisions we can talk.
+pub const SUPPORTED_PROTOCOLS: [&str; 1] = ["v2"];
+
+#[derive(Debug, Error, PartialEq)]
+pub enum HandshakeError {
+    #[error("Unsupported protocol `{0}`")]
+    UnsupportedProtocol(String),
+    #[error("Crypt `{0}` was not offered")]
+    CryptNotOffered(String),
+    #[error("Invalid fileserver port `{0}`")]
+    InvalidPort(usize),
+    #[error("Handshake has no time")]
+    MissingTime,
+}
+
+/// Connection parameters agreed on by a handshake exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    /// Crypt the connection switches to, `None` to stay in plaintext.
+    pub crypt: Option<String>,
+    /// Whether both sides encode binary as msgpack bin.
+    pub use_bin_type: bool,
+    pub protocol: String,
+    /// Seconds the remote's clock is ahead of ours, negative if behind.
+    pub clock_skew: i64,
+    /// Port the remote accepts connections on, `None` if it doesn't.
+    pub fileserver_port: Option<u16>,
+    /// Our address as the remote sees it, from its `target_ip`.
+    pub target_address: Option<String>,
+}
+
+impl Session {
+    /// Check the remote's handshake against ours and work out the session.
+    ///
+    /// Works on both sides of the exchange: the crypt is the one the
+    /// responder picked, which must be among those the requester offered.
+    /// The clock skew is measured against the time in `ours`, so build it
+    /// right before the exchange.
+    pub fn negotiate(ours: &Handshake, theirs: &Handshake) -> Result<Session, HandshakeError> {
+        if !SUPPORTED_PROTOCOLS.contains(&theirs.protocol.as_str()) {
+            return Err(HandshakeError::UnsupportedProtocol(theirs.protocol.clone()));
+        }
+        if theirs.time == 0 {
+            return Err(HandshakeError::MissingTime);
+        }
+        let fileserver_port = match theirs.fileserver_port {
+            0 => None,
+            port => Some(u16::try_from(port).map_err(|_| HandshakeError::InvalidPort(port))?),
+        };
+        let crypt = match (picked(ours), picked(theirs)) {
+            (Some(crypt), _) => Some(offered(crypt, theirs)?),
+            (None, Some(crypt)) => Some(offered(crypt, ours)?),
+            (None, None) => None,
+        };
+        Ok(Session {
+            crypt,
+            use_bin_type: ours.use_bin_type && theirs.use_bin_type,
+            protocol: theirs.protocol.clone(),
+            clock_skew: theirs.time as i64 - ours.time as i64,
+            fileserver_port,
+            target_address: theirs.target_address.clone().filter(|ip| !ip.is_empty()),
+        })
+    }
+}
+
+fn picked(handshake: &Handshake) -> Option<&str> {
+    handshake.crypt.as_deref().filter(|crypt| !crypt.is_empty())
+}
+
+fn offered(crypt: &str, offerer: &Handshake) -> Result<String, HandshakeError> {
+    match offerer.crypt_supported.iter().any(|name| name == crypt) {
+        true => Ok(crypt.to_string()),
+        false => Err(HandshakeError::CryptNotOffered(crypt.to_string())),
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+
+    fn handshake(time: u64) -> Handshake {
+        Handshake {
+            peer_id: "-DN0100-abcdefghijkl".into(),
+            fileserver_port: 15441,
+            time,
+            use_bin_type: true,
+            protocol: "v2".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut request = handshake(1_000);
+        request.crypt_supported = vec!["tls-rsa".into(), "tls-ecc".into()];
+        let mut response = handshake(990);
+        response.crypt = Some("tls-ecc".into());
+        response.use_bin_type = false;
+        response.target_address = Some("203.0.113.7".into());
+
+        let session = Session::negotiate(&request, &response).unwrap();
+        assert_eq!(session.crypt.as_deref(), Some("tls-ecc"));
+        assert!(!session.use_bin_type);
+        assert_eq!(session.clock_skew, -10);
+        assert_eq!(session.fileserver_port, Some(15441));
+        assert_eq!(session.target_address.as_deref(), Some("203.0.113.7"));
+        // The responder agrees on the same crypt.
+        let session = Session::negotiate(&response, &request).unwrap();
+        assert_eq!(session.crypt.as_deref(), Some("tls-ecc"));
+        assert_eq!(session.clock_skew, 10);
+    }
+
+    #[test]
+    fn test_negotiate_plaintext() {
+        let mut response = handshake(1_000);
+        response.fileserver_port = 0;
+        response.crypt = Some(String::new());
+        let session = Session::negotiate(&handshake(1_000), &response).unwrap();
+        assert_eq!(session.crypt, None);
+        assert_eq!(session.fileserver_port, None);
+        assert_eq!(session.target_address, None);
+    }
+
+    #[test]
+    fn test_negotiate_invalid() {
+        let ours = handshake(1_000);
+        let mut theirs = handshake(1_000);
+        theirs.crypt = Some("tls-rsa".into());
+        assert_eq!(
+            Session::negotiate(&ours, &theirs),
+            Err(HandshakeError::CryptNotOffered("tls-rsa".into()))
+        );
+
+        let mut theirs = handshake(1_000);
+        theirs.protocol = "v1".into();
+        assert_eq!(
+            Session::negotiate(&ours, &theirs),
+            Err(HandshakeError::UnsupportedProtocol("v1".into()))
+        );
+
+        let mut theirs = handshake(1_000);
+        theirs.fileserver_port = 70_000;
+        assert_eq!(
+            Session::negotiate(&ours, &theirs),
+            Err(HandshakeError::InvalidPort(70_000))
+        );
+
+        assert_eq!(
+            Session::negotiate(&ours, &handshake(0)),
+            Err(HandshakeError::MissingTime)
+        );
+    }
+}
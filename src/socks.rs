@@ -0,0 +1,497 @@
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    address::{AddressError, AsyncReader, AsyncWriter, BlockingReader, BlockingWriter, PeerAddr},
+    transport::Connector,
+};
+
+/// Address of the SOCKS5 proxy of a locally running Tor daemon.
+pub const TOR_SOCKS_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 9050);
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE: u8 = 0xff;
+const USER_PASS_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Username and password sent to the proxy.
+///
+/// Tor builds a separate circuit for every distinct pair, which makes
+/// them a cheap way to isolate streams from each other.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Where the proxy is asked to connect to.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Target {
+    Ip(SocketAddr),
+    /// Resolved by the proxy, which is what onion services need.
+    Domain(String, u16),
+}
+
+impl Target {
+    fn encode(&self, request: &mut Vec<u8>) -> Result<(), AddressError> {
+        let port = match self {
+            Target::Ip(SocketAddr::V4(address)) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&address.ip().octets());
+                address.port()
+            }
+            Target::Ip(SocketAddr::V6(address)) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&address.ip().octets());
+                address.port()
+            }
+            Target::Domain(host, port) => {
+                let len = u8::try_from(host.len())
+                    .map_err(|_| AddressError::ProxyHostTooLong(host.clone()))?;
+                request.push(ATYP_DOMAIN);
+                request.push(len);
+                request.extend_from_slice(host.as_bytes());
+                *port
+            }
+        };
+        request.extend_from_slice(&port.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl From<&PeerAddr> for Target {
+    fn from(address: &PeerAddr) -> Target {
+        match address {
+            PeerAddr::IPV4(ip, port) => Target::Ip(SocketAddr::new(IpAddr::from(*ip), *port)),
+            PeerAddr::IPV6(ip, port) => Target::Ip(SocketAddr::new(IpAddr::from(*ip), *port)),
+            // Everything else is a name only the proxy can resolve.
+            #[cfg(any(feature = "tor", feature = "i2p", feature = "loki"))]
+            _ => {
+                let name = address.to_string();
+                let host = name
+                    .rsplit_once(':')
+                    .map_or(name.as_str(), |(host, _)| host);
+                Target::Domain(host.to_string(), address.get_port())
+            }
+        }
+    }
+}
+
+/// An async SOCKS5 client.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), decentnet_protocol::address::AddressError> {
+/// use decentnet_protocol::{socks::Socks5Proxy, address::PeerAddr};
+///
+/// let proxy = Socks5Proxy::tor().isolated("site-a", "");
+/// let address = PeerAddr::parse("127.0.0.1:15441").unwrap();
+/// let stream = proxy.connect_addr(&address).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Socks5Proxy {
+    pub address: SocketAddr,
+    pub credentials: Option<Credentials>,
+}
+
+impl Socks5Proxy {
+    pub fn new(address: SocketAddr) -> Socks5Proxy {
+        Socks5Proxy {
+            address,
+            credentials: None,
+        }
+    }
+
+    /// The proxy of a Tor daemon listening on its default port.
+    pub fn tor() -> Socks5Proxy {
+        Socks5Proxy::new(SocketAddr::from(TOR_SOCKS_ADDR))
+    }
+
+    /// Authenticate with `username` and `password`.
+    pub fn isolated<U: Into<String>, P: Into<String>>(
+        mut self,
+        username: U,
+        password: P,
+    ) -> Socks5Proxy {
+        self.credentials = Some(Credentials {
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    /// Open a tunnel to `host:port`, letting the proxy resolve `host`.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, AddressError> {
+        self.connect_target(&Target::Domain(host.to_string(), port))
+            .await
+    }
+
+    /// Open a tunnel to a peer of any address family.
+    pub async fn connect_addr(&self, address: &PeerAddr) -> Result<TcpStream, AddressError> {
+        self.connect_target(&Target::from(address)).await
+    }
+
+    pub async fn connect_target(&self, target: &Target) -> Result<TcpStream, AddressError> {
+        let request = connect_request(target)?;
+
+        let mut stream = TcpStream::connect(self.address).await?;
+        self.authenticate(&mut stream).await?;
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        // The bound address is of no use to us, but has to be consumed.
+        let bound_len = match bound_len(&reply)? {
+            Some(len) => len,
+            None => stream.read_u8().await? as usize,
+        };
+        let mut bound = vec![0u8; bound_len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(stream)
+    }
+
+    /// Open a tunnel to `target` with blocking I/O, for use outside of
+    /// a runtime.
+    pub fn connect_target_blocking(
+        &self,
+        target: &Target,
+    ) -> Result<std::net::TcpStream, AddressError> {
+        let request = connect_request(target)?;
+
+        let mut stream = std::net::TcpStream::connect(self.address)?;
+        self.authenticate_blocking(&mut stream)?;
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        let bound_len = match bound_len(&reply)? {
+            Some(len) => len,
+            None => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+        };
+        let mut bound = vec![0u8; bound_len + 2];
+        stream.read_exact(&mut bound)?;
+
+        Ok(stream)
+    }
+
+    fn method(&self) -> u8 {
+        match self.credentials {
+            Some(_) => USER_PASS,
+            None => NO_AUTH,
+        }
+    }
+
+    async fn authenticate(&self, stream: &mut TcpStream) -> Result<(), AddressError> {
+        let method = self.method();
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        check_method(&reply, method)?;
+        let request = match &self.credentials {
+            Some(credentials) => auth_request(credentials)?,
+            None => return Ok(()),
+        };
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        check_auth(&reply)
+    }
+
+    fn authenticate_blocking(&self, stream: &mut std::net::TcpStream) -> Result<(), AddressError> {
+        let method = self.method();
+        stream.write_all(&[VERSION, 1, method])?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        check_method(&reply, method)?;
+        let request = match &self.credentials {
+            Some(credentials) => auth_request(credentials)?,
+            None => return Ok(()),
+        };
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        check_auth(&reply)
+    }
+}
+
+fn connect_request(target: &Target) -> Result<Vec<u8>, AddressError> {
+    let mut request = vec![VERSION, CMD_CONNECT, 0];
+    target.encode(&mut request)?;
+    Ok(request)
+}
+
+fn check_method(reply: &[u8; 2], method: u8) -> Result<(), AddressError> {
+    if reply[0] != VERSION {
+        return Err(AddressError::ProxyProtocol);
+    }
+    if reply[1] == NO_ACCEPTABLE {
+        return Err(AddressError::ProxyAuth);
+    }
+    if reply[1] != method {
+        return Err(AddressError::ProxyProtocol);
+    }
+    Ok(())
+}
+
+fn auth_request(credentials: &Credentials) -> Result<Vec<u8>, AddressError> {
+    let username = credentials.username.as_bytes();
+    let password = credentials.password.as_bytes();
+    let (username_len, password_len) =
+        match (u8::try_from(username.len()), u8::try_from(password.len())) {
+            (Ok(username_len), Ok(password_len)) => (username_len, password_len),
+            _ => return Err(AddressError::ProxyAuth),
+        };
+    let mut request = vec![USER_PASS_VERSION, username_len];
+    request.extend_from_slice(username);
+    request.push(password_len);
+    request.extend_from_slice(password);
+    Ok(request)
+}
+
+fn check_auth(reply: &[u8; 2]) -> Result<(), AddressError> {
+    if reply[0] != USER_PASS_VERSION {
+        return Err(AddressError::ProxyProtocol);
+    }
+    if reply[1] != 0 {
+        return Err(AddressError::ProxyAuth);
+    }
+    Ok(())
+}
+
+/// Length of the bound address that follows a CONNECT reply, or `None`
+/// for a domain, whose length is the next byte.
+fn bound_len(reply: &[u8; 4]) -> Result<Option<usize>, AddressError> {
+    if reply[0] != VERSION {
+        return Err(AddressError::ProxyProtocol);
+    }
+    if reply[1] != 0 {
+        return Err(AddressError::ProxyRefused(reply[1]));
+    }
+    match reply[3] {
+        ATYP_IPV4 => Ok(Some(4)),
+        ATYP_IPV6 => Ok(Some(16)),
+        ATYP_DOMAIN => Ok(None),
+        _ => Err(AddressError::ProxyProtocol),
+    }
+}
+
+#[async_trait::async_trait]
+impl Connector for Socks5Proxy {
+    async fn connect(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(AsyncReader, AsyncWriter), AddressError> {
+        let (reader, writer) = self.connect_addr(address).await?.into_split();
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    fn connect_blocking(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(BlockingReader, BlockingWriter), AddressError> {
+        let stream = self.connect_target_blocking(&Target::from(address))?;
+        Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// What a client sent to `fake_proxy`.
+    struct Seen {
+        credentials: Option<(Vec<u8>, Vec<u8>)>,
+        target: Vec<u8>,
+    }
+
+    /// Accept one client and answer its CONNECT request with `rep`,
+    /// requiring `username`/`password` if they are given.
+    async fn fake_proxy(
+        rep: u8,
+        credentials: Option<(&'static str, &'static str)>,
+    ) -> (SocketAddr, tokio::task::JoinHandle<Seen>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 2];
+            socket.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0u8; greeting[1] as usize];
+            socket.read_exact(&mut methods).await.unwrap();
+
+            let mut seen = Seen {
+                credentials: None,
+                target: vec![],
+            };
+            if let Some((username, password)) = credentials {
+                if !methods.contains(&USER_PASS) {
+                    socket.write_all(&[VERSION, NO_ACCEPTABLE]).await.unwrap();
+                    return seen;
+                }
+                socket.write_all(&[VERSION, USER_PASS]).await.unwrap();
+                assert_eq!(socket.read_u8().await.unwrap(), USER_PASS_VERSION);
+                let mut user = vec![0u8; socket.read_u8().await.unwrap() as usize];
+                socket.read_exact(&mut user).await.unwrap();
+                let mut pass = vec![0u8; socket.read_u8().await.unwrap() as usize];
+                socket.read_exact(&mut pass).await.unwrap();
+                let status = (user != username.as_bytes() || pass != password.as_bytes()) as u8;
+                socket
+                    .write_all(&[USER_PASS_VERSION, status])
+                    .await
+                    .unwrap();
+                seen.credentials = Some((user, pass));
+                if status != 0 {
+                    return seen;
+                }
+            } else {
+                assert!(methods.contains(&NO_AUTH));
+                socket.write_all(&[VERSION, NO_AUTH]).await.unwrap();
+            }
+
+            let mut head = [0u8; 4];
+            socket.read_exact(&mut head).await.unwrap();
+            assert_eq!(head[..3], [VERSION, CMD_CONNECT, 0]);
+            let len = match head[3] {
+                ATYP_IPV4 => 4,
+                ATYP_IPV6 => 16,
+                _ => socket.read_u8().await.unwrap() as usize,
+            };
+            seen.target = vec![0u8; len + 2];
+            socket.read_exact(&mut seen.target).await.unwrap();
+            seen.target.insert(0, head[3]);
+            socket
+                .write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            if rep == 0 {
+                socket.write_all(b"hello").await.unwrap();
+            }
+            seen
+        });
+        (addr, task)
+    }
+
+    #[tokio::test]
+    async fn test_connect_hostname() {
+        let (proxy, task) = fake_proxy(0, None).await;
+        let proxy = Socks5Proxy::new(proxy);
+        let mut stream = proxy.connect("example.onion", 15441).await.unwrap();
+        let mut greeting = [0u8; 5];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        let seen = task.await.unwrap();
+        assert_eq!(seen.target[0], ATYP_DOMAIN);
+        assert_eq!(&seen.target[1..14], b"example.onion");
+        assert_eq!(seen.target[14..], 15441u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_connect_clearnet() {
+        let (proxy, task) = fake_proxy(0, None).await;
+        let address = PeerAddr::parse("10.0.0.1:15441").unwrap();
+        let (mut reader, _writer) = Connector::connect(&Socks5Proxy::new(proxy), &address)
+            .await
+            .unwrap();
+        let mut greeting = [0u8; 5];
+        reader.read_exact(&mut greeting).await.unwrap();
+
+        let seen = task.await.unwrap();
+        assert_eq!(seen.target, [ATYP_IPV4, 10, 0, 0, 1, 0x3c, 0x51]);
+    }
+
+    #[cfg(feature = "tor")]
+    #[tokio::test]
+    async fn test_connect_onion() {
+        let (proxy, task) = fake_proxy(0, None).await;
+        let address = PeerAddr::parse("ytcnzluhaxidtbf4.onion:15441").unwrap();
+        Socks5Proxy::new(proxy)
+            .connect_addr(&address)
+            .await
+            .unwrap();
+
+        let seen = task.await.unwrap();
+        assert_eq!(&seen.target[1..23], b"ytcnzluhaxidtbf4.onion");
+        assert_eq!(seen.target[23..], 15441u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_credentials() {
+        let (proxy, task) = fake_proxy(0, Some(("user", "secret"))).await;
+        let proxy = Socks5Proxy::new(proxy).isolated("user", "secret");
+        proxy.connect("example.onion", 15441).await.unwrap();
+        let seen = task.await.unwrap();
+        assert_eq!(
+            seen.credentials,
+            Some((b"user".to_vec(), b"secret".to_vec()))
+        );
+
+        let (proxy, _task) = fake_proxy(0, Some(("user", "secret"))).await;
+        let proxy = Socks5Proxy::new(proxy).isolated("user", "wrong");
+        let result = proxy.connect("example.onion", 15441).await;
+        assert!(matches!(result, Err(AddressError::ProxyAuth)));
+
+        let (proxy, _task) = fake_proxy(0, Some(("user", "secret"))).await;
+        let result = Socks5Proxy::new(proxy).connect("example.onion", 1).await;
+        assert!(matches!(result, Err(AddressError::ProxyAuth)));
+    }
+
+    #[tokio::test]
+    async fn test_connect_blocking() {
+        let (proxy, task) = fake_proxy(0, Some(("user", "secret"))).await;
+        let proxy = Socks5Proxy::new(proxy).isolated("user", "secret");
+        let address = PeerAddr::parse("10.0.0.1:15441").unwrap();
+        let greeting = tokio::task::spawn_blocking(move || {
+            let (mut reader, _writer) = proxy.connect_blocking(&address).unwrap();
+            let mut greeting = [0u8; 5];
+            reader.read_exact(&mut greeting).unwrap();
+            greeting
+        })
+        .await
+        .unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        let seen = task.await.unwrap();
+        assert_eq!(seen.target, [ATYP_IPV4, 10, 0, 0, 1, 0x3c, 0x51]);
+
+        let (proxy, _task) = fake_proxy(0x05, None).await;
+        let result = tokio::task::spawn_blocking(move || {
+            Socks5Proxy::new(proxy)
+                .connect_target_blocking(&Target::Domain("example.onion".into(), 1))
+        })
+        .await
+        .unwrap();
+        assert!(matches!(result, Err(AddressError::ProxyRefused(0x05))));
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused() {
+        let (proxy, _task) = fake_proxy(0x05, None).await;
+        let result = Socks5Proxy::new(proxy)
+            .connect("example.onion", 15441)
+            .await;
+        assert!(matches!(result, Err(AddressError::ProxyRefused(0x05))));
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::utils::is_default;
+use crate::utils::{is_default, map_or_empty_seq};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use serde_json::Value;
@@ -93,6 +93,8 @@ pub struct Pex {
     pub peers_onion: Option<Vec<ByteBuf>>,
     #[serde(skip_serializing_if = "is_default")]
     pub peers_ipv6: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub peers_loki: Option<Vec<ByteBuf>>,
     pub need: usize,
 }
 
@@ -101,6 +103,8 @@ pub struct PexResponse {
     pub peers: Vec<ByteBuf>,
     pub peers_ipv6: Vec<ByteBuf>,
     pub peers_onion: Vec<ByteBuf>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub peers_loki: Vec<ByteBuf>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -174,6 +178,46 @@ pub struct CheckportResponse {
     pub ip_external: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Announce {
+    pub hashes: Vec<ByteBuf>,
+    pub port: u16,
+    pub need_types: Vec<String>,
+    pub need_num: usize,
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub delete: bool,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub onions: Vec<String>,
+    /// Signature of `onion_sign_this` by each announced onion.
+    #[serde(default, deserialize_with = "map_or_empty_seq")]
+    pub onion_signs: HashMap<String, ByteBuf>,
+    #[serde(default)]
+    pub onion_sign_this: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct AnnouncedPeers {
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub ipv4: Vec<ByteBuf>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub ipv6: Vec<ByteBuf>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub onion: Vec<ByteBuf>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub loki: Vec<ByteBuf>,
+}
+
+/// Packed peers found for each announced hash, in the order of `hashes`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnnounceResponse {
+    pub peers: Vec<AnnouncedPeers>,
+    /// Nonce the announced onions have to sign, sent back when their
+    /// `onion_signs` were missing or invalid.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub onion_sign_this: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GetPieceFields {
     pub site: String,
@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{aws_lc_rs, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::{
+    address::{AsyncReader, AsyncWriter, PeerAddr},
+    command::Command,
+    error::Error,
+    external_ip::is_public,
+    message::{MessageReader, MessageWriter, ZeroMessage},
+    templates::Handshake,
+    utils::Either,
+};
+
+/// Transport encryption a peer may offer in `crypt_supported`.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum Crypt {
+    TlsRsa,
+    TlsEcc,
+}
+
+impl Crypt {
+    /// In order of preference when we pick for a remote.
+    pub const SUPPORTED: [Crypt; 2] = [Crypt::TlsRsa, Crypt::TlsEcc];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Crypt::TlsRsa => "tls-rsa",
+            Crypt::TlsEcc => "tls-ecc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Crypt> {
+        Crypt::SUPPORTED
+            .into_iter()
+            .find(|crypt| crypt.name() == name)
+    }
+
+    fn algorithm(self) -> &'static rcgen::SignatureAlgorithm {
+        match self {
+            Crypt::TlsRsa => &rcgen::PKCS_RSA_SHA256,
+            Crypt::TlsEcc => &rcgen::PKCS_ECDSA_P256_SHA256,
+        }
+    }
+}
+
+/// The first of `ours` that the remote also supports.
+pub fn select_crypt(ours: &[Crypt], theirs: &[String]) -> Option<Crypt> {
+    ours.iter()
+        .copied()
+        .find(|crypt| theirs.iter().any(|name| name == crypt.name()))
+}
+
+/// Whether connections to `remote` are worth encrypting. Onion and other
+/// overlay networks already encrypt end to end.
+pub fn should_encrypt(remote: &PeerAddr) -> bool {
+    remote.is_clearnet()
+}
+
+/// TLS configuration shared by all connections.
+///
+/// Server certificates are self-signed and generated the first time a
+/// remote picks their crypt. Like ZeroNet, the client accepts any
+/// certificate: the encryption only protects against passive listeners.
+pub struct TlsContext {
+    provider: Arc<CryptoProvider>,
+    client: Arc<ClientConfig>,
+    servers: Mutex<HashMap<Crypt, Arc<ServerConfig>>>,
+}
+
+impl TlsContext {
+    pub fn new() -> Result<TlsContext, Error> {
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let client = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider.clone())))
+            .with_no_client_auth();
+        Ok(TlsContext {
+            provider,
+            client: Arc::new(client),
+            servers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Names to advertise in `crypt_supported`.
+    pub fn supported(&self) -> Vec<String> {
+        Crypt::SUPPORTED
+            .iter()
+            .map(|crypt| crypt.name().to_string())
+            .collect()
+    }
+
+    fn server_config(&self, crypt: Crypt) -> Result<Arc<ServerConfig>, Error> {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(config) = servers.get(&crypt) {
+            return Ok(config.clone());
+        }
+        let key_pair = rcgen::KeyPair::generate_for(crypt.algorithm())?;
+        let params = rcgen::CertificateParams::new(vec!["example.com".to_string()])?;
+        let cert = params.self_signed(&key_pair)?;
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+        let config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key)?;
+        let config = Arc::new(config);
+        servers.insert(crypt, config.clone());
+        Ok(config)
+    }
+
+    /// Upgrade the dialing side of a connection to `remote`.
+    pub async fn connect<S>(
+        &self,
+        remote: &PeerAddr,
+        stream: S,
+    ) -> Result<(AsyncReader, AsyncWriter), Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let socket: SocketAddr = remote.try_into()?;
+        let server_name = ServerName::IpAddress(socket.ip().into());
+        let stream = TlsConnector::from(self.client.clone())
+            .connect(server_name, stream)
+            .await?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    /// Upgrade the accepting side of a connection with a certificate for
+    /// `crypt`.
+    pub async fn accept<S>(
+        &self,
+        crypt: Crypt,
+        stream: S,
+    ) -> Result<(AsyncReader, AsyncWriter), Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let stream = TlsAcceptor::from(self.server_config(crypt)?)
+            .accept(stream)
+            .await?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+/// Send our handshake to `remote`, wait for theirs and switch to the crypt
+/// it picked, if any.
+///
+/// Returns the transport to build a `Connection` on and the remote's
+/// handshake.
+pub async fn dial<R, W>(
+    tls: &TlsContext,
+    remote: &PeerAddr,
+    reader: R,
+    writer: W,
+    handshake: &Handshake,
+) -> Result<(AsyncReader, AsyncWriter, Handshake), Error>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let mut handshake = handshake.clone();
+    handshake.crypt = None;
+    handshake.crypt_supported = match should_encrypt(remote) {
+        true => tls.supported(),
+        false => vec![],
+    };
+    let mut writer = MessageWriter::new(writer);
+    writer.send_request(Handshake::NAME, 0, &handshake).await?;
+
+    let mut reader = MessageReader::new(reader);
+    let response = match reader.next().await? {
+        Some(ZeroMessage::Response(response)) if response.to == 0 => response,
+        Some(_) => return Err(Error::UnexpectedMessage),
+        None => return Err(Error::ConnectionClosed),
+    };
+    let remote_handshake = match response.decode_for::<Handshake>()? {
+        Either::Success(handshake) => handshake,
+        Either::Error(error) => return Err(Error::Remote(error.error)),
+    };
+
+    let encrypt = match remote_handshake.crypt.as_deref() {
+        None | Some("") => false,
+        Some(name)
+            if handshake
+                .crypt_supported
+                .iter()
+                .any(|offered| offered == name) =>
+        {
+            true
+        }
+        Some(name) => return Err(Error::UnsupportedCrypt(name.to_string())),
+    };
+    let reader = rejoin(reader);
+    let writer = writer.into_inner();
+    let (reader, writer) = match encrypt {
+        // The certificate the remote presents decides between RSA and ECC.
+        true => tls.connect(remote, tokio::io::join(reader, writer)).await?,
+        false => (
+            Box::new(reader) as AsyncReader,
+            Box::new(writer) as AsyncWriter,
+        ),
+    };
+    Ok((reader, writer, remote_handshake))
+}
+
+/// Answer the handshake of `remote` with ours, picking a crypt among the
+/// ones it offered, and switch to it. Connections that `should_encrypt`
+/// rules out, local ones and ones whose handshake carries an `onion` stay
+/// in plaintext whatever the remote offers.
+///
+/// Returns the transport to build a `Connection` on and the remote's
+/// handshake.
+pub async fn accept<R, W>(
+    tls: &TlsContext,
+    remote: &PeerAddr,
+    reader: R,
+    writer: W,
+    handshake: &Handshake,
+) -> Result<(AsyncReader, AsyncWriter, Handshake), Error>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let mut reader = MessageReader::new(reader);
+    let request = match reader.next().await? {
+        Some(ZeroMessage::Request(request)) => request,
+        Some(_) => return Err(Error::UnexpectedMessage),
+        None => return Err(Error::ConnectionClosed),
+    };
+    let remote_handshake: Handshake = request.params_as()?;
+
+    // Hidden services get their onion connections from loopback, so the
+    // socket address alone can't tell them apart.
+    let socket: Result<SocketAddr, _> = remote.try_into();
+    let local = match socket {
+        Ok(socket) => !is_public(socket.ip()),
+        Err(_) => false,
+    };
+    let onion = remote_handshake
+        .onion
+        .as_deref()
+        .is_some_and(|onion| !onion.is_empty());
+    let encrypt = should_encrypt(remote) && !local && !onion;
+    let crypt = match encrypt {
+        true => select_crypt(&Crypt::SUPPORTED, &remote_handshake.crypt_supported),
+        false => None,
+    };
+    let mut handshake = handshake.clone();
+    handshake.crypt = crypt.map(|crypt| crypt.name().to_string());
+    handshake.crypt_supported = match encrypt {
+        true => tls.supported(),
+        false => vec![],
+    };
+    let mut writer = MessageWriter::new(writer);
+    writer.send_response(request.req_id, &handshake).await?;
+
+    let reader = rejoin(reader);
+    let writer = writer.into_inner();
+    let (reader, writer) = match crypt {
+        Some(crypt) => tls.accept(crypt, tokio::io::join(reader, writer)).await?,
+        None => (
+            Box::new(reader) as AsyncReader,
+            Box::new(writer) as AsyncWriter,
+        ),
+    };
+    Ok((reader, writer, remote_handshake))
+}
+
+/// The reader with whatever `MessageReader` had buffered put back in front.
+fn rejoin<R: AsyncRead + Send + Unpin + 'static>(
+    reader: MessageReader<R>,
+) -> impl AsyncRead + Send + Unpin + 'static {
+    let (reader, buffered) = reader.into_inner();
+    Cursor::new(buffered).chain(reader)
+}
+
+/// Accepts the self-signed certificates peers use, while still checking
+/// that the handshake is signed by the certificate's key.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_select_crypt() {
+        let theirs = vec!["tls-ecc".to_string(), "tls-rsa".to_string()];
+        assert_eq!(
+            select_crypt(&Crypt::SUPPORTED, &theirs),
+            Some(Crypt::TlsRsa)
+        );
+        let theirs = vec!["tls-ecc".to_string()];
+        assert_eq!(
+            select_crypt(&Crypt::SUPPORTED, &theirs),
+            Some(Crypt::TlsEcc)
+        );
+        assert_eq!(select_crypt(&Crypt::SUPPORTED, &["none".to_string()]), None);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_to_tls() {
+        let tls = Arc::new(TlsContext::new().unwrap());
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        let server_tls = tls.clone();
+        let server = tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(remote);
+            let dialer = PeerAddr::parse("93.184.216.34:26552").unwrap();
+            let (reader, mut writer, dialer) =
+                accept(&server_tls, &dialer, reader, writer, &Handshake::default())
+                    .await
+                    .unwrap();
+            writer.write_all(b"hello").await.unwrap();
+            writer.flush().await.unwrap();
+            (dialer, reader, writer)
+        });
+
+        let (reader, writer) = tokio::io::split(local);
+        let address = PeerAddr::parse("127.0.0.1:15441").unwrap();
+        let (mut reader, _writer, accepter) =
+            dial(&tls, &address, reader, writer, &Handshake::default())
+                .await
+                .unwrap();
+        let mut greeting = [0u8; 5];
+        reader.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        let (dialer, _, _) = server.await.unwrap();
+        assert_eq!(dialer.crypt_supported, ["tls-rsa", "tls-ecc"]);
+        assert_eq!(accepter.crypt.as_deref(), Some("tls-rsa"));
+    }
+
+    #[cfg(feature = "tor")]
+    #[tokio::test]
+    async fn test_onion_stays_plain() {
+        let tls = TlsContext::new().unwrap();
+        let (local, remote) = tokio::io::duplex(1024);
+        let (remote_reader, remote_writer) = tokio::io::split(remote);
+        let address = PeerAddr::parse("ytcnzluhaxidtbf4.onion:15441").unwrap();
+        let dialer = address.clone();
+        let server = tokio::spawn(async move {
+            let tls = TlsContext::new().unwrap();
+            accept(
+                &tls,
+                &dialer,
+                remote_reader,
+                remote_writer,
+                &Handshake::default(),
+            )
+            .await
+            .unwrap()
+            .2
+        });
+        let (reader, writer) = tokio::io::split(local);
+        let (_, _, accepter) = dial(&tls, &address, reader, writer, &Handshake::default())
+            .await
+            .unwrap();
+        assert_eq!(accepter.crypt, None);
+        assert!(server.await.unwrap().crypt_supported.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_accept_onion_stays_plain() {
+        let tls = TlsContext::new().unwrap();
+        let (local, remote) = tokio::io::duplex(1024);
+        let (remote_reader, remote_writer) = tokio::io::split(remote);
+        let server = tokio::spawn(async move {
+            let tls = TlsContext::new().unwrap();
+            // Onion connections reach a hidden service from loopback.
+            let dialer = PeerAddr::parse("127.0.0.1:26552").unwrap();
+            accept(
+                &tls,
+                &dialer,
+                remote_reader,
+                remote_writer,
+                &Handshake::default(),
+            )
+            .await
+            .unwrap()
+            .2
+        });
+        // The dialer offers TLS anyway, but announces its onion.
+        let (reader, writer) = tokio::io::split(local);
+        let address = PeerAddr::parse("93.184.216.34:15441").unwrap();
+        let handshake = Handshake {
+            onion: Some("ytcnzluhaxidtbf4".to_string()),
+            ..Handshake::default()
+        };
+        let (_, _, accepter) = dial(&tls, &address, reader, writer, &handshake)
+            .await
+            .unwrap();
+        assert_eq!(accepter.crypt, None);
+        assert!(accepter.crypt_supported.is_empty());
+        let dialer = server.await.unwrap();
+        assert_eq!(dialer.onion.as_deref(), Some("ytcnzluhaxidtbf4"));
+        assert_eq!(dialer.crypt_supported, ["tls-rsa", "tls-ecc"]);
+    }
+}
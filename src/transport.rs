@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+};
+
+#[cfg(feature = "tor")]
+use crate::socks::Socks5Proxy;
+use crate::{
+    address::{
+        AddrKind, AddressError, AsyncReader, AsyncWriter, BlockingReader, BlockingWriter, PeerAddr,
+    },
+    connection::Connection,
+    error::Error,
+};
+
+/// Opens transports to one or more address families.
+///
+/// Register implementations on `Connectors` to reach addresses through a
+/// custom proxy, a SAM bridge or an in-memory transport in tests.
+/// `Socks5Proxy` is one, and can also route clearnet addresses through Tor.
+#[async_trait::async_trait]
+pub trait Connector: Send + Sync + 'static {
+    async fn connect(&self, address: &PeerAddr)
+        -> Result<(AsyncReader, AsyncWriter), AddressError>;
+
+    /// Open a transport to `address` blocking the current thread, for
+    /// `PeerAddr::get_pair`. Connectors that can only work within a
+    /// runtime leave it unsupported.
+    fn connect_blocking(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(BlockingReader, BlockingWriter), AddressError> {
+        Err(AddressError::NoBlockingConnector(address.kind()))
+    }
+}
+
+fn blocking_pair(stream: TcpStream) -> Result<(BlockingReader, BlockingWriter), AddressError> {
+    Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+}
+
+/// Plain TCP, for clearnet addresses.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpConnector;
+
+#[async_trait::async_trait]
+impl Connector for TcpConnector {
+    async fn connect(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(AsyncReader, AsyncWriter), AddressError> {
+        let socket: SocketAddr = address.try_into()?;
+        let (reader, writer) = tokio::net::TcpStream::connect(socket).await?.into_split();
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    fn connect_blocking(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(BlockingReader, BlockingWriter), AddressError> {
+        let socket: SocketAddr = address.try_into()?;
+        blocking_pair(TcpStream::connect(socket)?)
+    }
+}
+
+/// Lokinet addresses, which the system resolver maps to addresses on the
+/// Lokinet interface when Lokinet is running.
+#[cfg(feature = "loki")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LokiConnector;
+
+#[cfg(feature = "loki")]
+#[async_trait::async_trait]
+impl Connector for LokiConnector {
+    async fn connect(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(AsyncReader, AsyncWriter), AddressError> {
+        if !address.is_loki() {
+            return Err(AddressError::InvalidAddressType);
+        }
+        let stream = tokio::net::TcpStream::connect(address.to_string()).await?;
+        let (reader, writer) = stream.into_split();
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    fn connect_blocking(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(BlockingReader, BlockingWriter), AddressError> {
+        if !address.is_loki() {
+            return Err(AddressError::InvalidAddressType);
+        }
+        blocking_pair(TcpStream::connect(address.to_string())?)
+    }
+}
+
+/// Which `Connector` handles each kind of address.
+///
+/// The default registry dials clearnet addresses over TCP and, with their
+/// features, onion addresses through the local Tor proxy and Lokinet
+/// addresses through the local Lokinet. Other families have no connector
+/// until one is registered.
+#[derive(Clone)]
+pub struct Connectors {
+    connectors: HashMap<AddrKind, Arc<dyn Connector>>,
+}
+
+impl Connectors {
+    /// A registry without any connector.
+    pub fn empty() -> Connectors {
+        Connectors {
+            connectors: HashMap::new(),
+        }
+    }
+
+    /// Use `connector` for addresses of `kind`, replacing any previous one.
+    pub fn register<C: Connector>(&mut self, kind: AddrKind, connector: C) -> &mut Connectors {
+        self.connectors.insert(kind, Arc::new(connector));
+        self
+    }
+
+    pub fn unregister(&mut self, kind: AddrKind) -> Option<Arc<dyn Connector>> {
+        self.connectors.remove(&kind)
+    }
+
+    pub fn get(&self, kind: AddrKind) -> Option<&Arc<dyn Connector>> {
+        self.connectors.get(&kind)
+    }
+
+    /// Open a transport to `address` with the connector registered for
+    /// its kind.
+    pub async fn connect(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(AsyncReader, AsyncWriter), AddressError> {
+        let kind = address.kind();
+        let connector = self.get(kind).ok_or(AddressError::NoConnector(kind))?;
+        connector.connect(address).await
+    }
+
+    /// Open a blocking transport to `address` with the connector
+    /// registered for its kind.
+    pub fn connect_blocking(
+        &self,
+        address: &PeerAddr,
+    ) -> Result<(BlockingReader, BlockingWriter), AddressError> {
+        let kind = address.kind();
+        let connector = self.get(kind).ok_or(AddressError::NoConnector(kind))?;
+        connector.connect_blocking(address)
+    }
+
+    /// Open a transport to `address` and wrap it in a `Connection`.
+    pub async fn open(&self, address: &PeerAddr) -> Result<Connection, Error> {
+        let (reader, writer) = self.connect(address).await?;
+        Ok(Connection::new(reader, writer))
+    }
+}
+
+impl Default for Connectors {
+    fn default() -> Connectors {
+        let mut connectors = Connectors::empty();
+        connectors
+            .register(AddrKind::IPV4, TcpConnector)
+            .register(AddrKind::IPV6, TcpConnector);
+        #[cfg(feature = "tor")]
+        connectors
+            .register(AddrKind::OnionV2, Socks5Proxy::tor())
+            .register(AddrKind::OnionV3, Socks5Proxy::tor());
+        #[cfg(feature = "loki")]
+        connectors.register(AddrKind::Loki, LokiConnector);
+        connectors
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Hands out the local end of an in-memory pipe and keeps the other.
+    #[derive(Default)]
+    struct MemoryConnector {
+        remote: Arc<Mutex<Vec<(PeerAddr, DuplexStream)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Connector for MemoryConnector {
+        async fn connect(
+            &self,
+            address: &PeerAddr,
+        ) -> Result<(AsyncReader, AsyncWriter), AddressError> {
+            let (local, remote) = tokio::io::duplex(64);
+            self.remote.lock().unwrap().push((address.clone(), remote));
+            let (reader, writer) = tokio::io::split(local);
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registered_connector() {
+        let connector = MemoryConnector::default();
+        let remote = connector.remote.clone();
+        let mut connectors = Connectors::empty();
+        connectors.register(AddrKind::IPV4, connector);
+
+        let address = PeerAddr::parse("10.0.0.1:15441").unwrap();
+        let (mut reader, mut writer) = connectors.connect(&address).await.unwrap();
+        writer.write_all(b"ping").await.unwrap();
+
+        let (dialed, mut stream) = remote.lock().unwrap().pop().unwrap();
+        assert_eq!(dialed, address);
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        stream.write_all(b"pong").await.unwrap();
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_missing_connector() {
+        let mut connectors = Connectors::default();
+        connectors.unregister(AddrKind::IPV6);
+        let address = PeerAddr::parse("[::1]:15441").unwrap();
+        let result = connectors.connect(&address).await;
+        assert!(matches!(
+            result,
+            Err(AddressError::NoConnector(AddrKind::IPV6))
+        ));
+    }
+}
@@ -1,10 +1,23 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{
+        value::{Error as DeError, MapAccessDeserializer, MapDeserializer, SeqDeserializer},
+        Error as _, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+    },
+    forward_to_deserialize_any, Deserialize, Deserializer, Serialize,
+};
 use serde_bytes::ByteBuf;
 use serde_json::Number;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+use crate::error::Error;
+
+/// Undecoded msgpack value, kept until the caller picks a type for it.
+///
+/// Binary and string values are kept apart, and `&Value` is itself a
+/// `Deserializer`, so typed bodies are decoded straight from it and may
+/// borrow its strings and bytes.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub(crate) enum Value {
     Null,
@@ -22,10 +35,311 @@ impl Default for Value {
     }
 }
 
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any msgpack value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+        Ok(Number::from_f64(v).map_or(Value::Null, Value::Number))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(ByteBuf::from(v)))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(ByteBuf::from(v)))
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut values = HashMap::new();
+        while let Some((MapKey(key), value)) = map.next_entry()? {
+            values.insert(key, value);
+        }
+        Ok(Value::Object(values))
+    }
+}
+
+/// Map key of a `Value::Object`. Integer keys, as sent for hash ids, are
+/// kept in their decimal form and parsed back when decoding.
+struct MapKey(String);
+
+impl<'de> Deserialize<'de> for MapKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MapKeyVisitor;
+
+        impl<'de> Visitor<'de> for MapKeyVisitor {
+            type Value = MapKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or integer map key")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<MapKey, E> {
+                Ok(MapKey(v.to_string()))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<MapKey, E> {
+                let key = std::str::from_utf8(v).map_err(E::custom)?;
+                Ok(MapKey(key.to_string()))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<MapKey, E> {
+                Ok(MapKey(v.to_string()))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<MapKey, E> {
+                Ok(MapKey(v.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(MapKeyVisitor)
+    }
+}
+
+/// Convert any serializable value into a `Value` by way of msgpack.
+pub(crate) fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    let bytes = rmp_serde::to_vec_named(value)?;
+    Ok(rmp_serde::from_slice(&bytes)?)
+}
+
+/// Decode a `T` from the entries of a map, borrowing from it where `T` allows.
+pub(crate) fn from_map<'de, T: Deserialize<'de>>(
+    map: &'de HashMap<String, Value>,
+) -> Result<T, DeError> {
+    let mut entries = map_deserializer(map);
+    let value = T::deserialize(MapAccessDeserializer::new(&mut entries))?;
+    entries.end()?;
+    Ok(value)
+}
+
+fn map_deserializer(
+    map: &HashMap<String, Value>,
+) -> MapDeserializer<'_, impl Iterator<Item = (BorrowedKey<'_>, &Value)>, DeError> {
+    MapDeserializer::new(map.iter().map(|(key, value)| (BorrowedKey(key), value)))
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for &'de Value {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> Deserializer<'de> for &'de Value {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(v) => visitor.visit_bool(*v),
+            Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(v), _) => visitor.visit_u64(v),
+                (None, Some(v)) => visitor.visit_i64(v),
+                _ => visitor.visit_f64(n.as_f64().unwrap_or_default()),
+            },
+            Value::String(v) => visitor.visit_borrowed_str(v),
+            Value::Bytes(v) => visitor.visit_borrowed_bytes(v),
+            Value::Array(values) => {
+                let mut seq = SeqDeserializer::new(values.iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Value::Object(map) => {
+                let mut entries = map_deserializer(map);
+                let value = visitor.visit_map(&mut entries)?;
+                entries.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        match self {
+            Value::String(variant) => visitor.visit_enum(variant.as_str().into_deserializer()),
+            Value::Object(map) if map.len() == 1 => {
+                visitor.visit_enum(MapAccessDeserializer::new(map_deserializer(map)))
+            }
+            _ => Err(DeError::custom("expected an enum variant")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// Key of a borrowed `Value::Object`, parsed as an integer when the
+/// target asks for one.
+struct BorrowedKey<'de>(&'de str);
+
+impl<'de> IntoDeserializer<'de, DeError> for BorrowedKey<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed_key {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                match self.0.parse() {
+                    Ok(key) => visitor.$visit(key),
+                    Err(_) => visitor.visit_borrowed_str(self.0),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for BorrowedKey<'de> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    deserialize_parsed_key! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i128 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
 pub(crate) fn is_default<T: Default + PartialEq>(t: &T) -> bool {
     t == &T::default()
 }
 
+/// Deserialize a map that some peers send as an empty list when it has
+/// no entries.
+pub(crate) fn map_or_empty_seq<'de, D, V>(deserializer: D) -> Result<HashMap<String, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    struct MapOrEmptySeq<V>(std::marker::PhantomData<V>);
+
+    impl<'de, V: Deserialize<'de>> Visitor<'de> for MapOrEmptySeq<V> {
+        type Value = HashMap<String, V>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a map or an empty sequence")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut result = HashMap::new();
+            while let Some((key, value)) = map.next_entry()? {
+                result.insert(key, value);
+            }
+            Ok(result)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            match seq.next_element::<serde::de::IgnoredAny>()? {
+                None => Ok(HashMap::new()),
+                Some(_) => Err(serde::de::Error::invalid_length(1, &self)),
+            }
+        }
+    }
+
+    deserializer.deserialize_any(MapOrEmptySeq(std::marker::PhantomData))
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum Either<Left, Right> {